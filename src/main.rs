@@ -1,210 +1,2797 @@
 use tokio::net::TcpStream;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 struct IrcConfig {
-    server: String,
-    port: u16,
+    // Tried in order at connect time until one accepts; usually just one entry. No TLS support,
+    // so there's no per-server TLS flag
+    servers: Vec<(String, u16)>,
+    // How long to wait on each server before moving on to the next
+    connect_timeout: Duration,
     nick: String,
     username: String,
     realname: String,
-    channels: Vec<String>
+    channels: Vec<String>,
+    // Appended to every line we send. "\r\n" per the RFC, or "\n" for test harnesses and gateways that want it
+    line_terminator: String,
+    webirc: Option<WebircConfig>,
+    // Sent with PASS during registration. Networks whose services accept PASS as account
+    // credentials can use this in place of SASL, which this client does not implement
+    server_password: Option<String>,
+    // Sent as the hostname field of USER, "*" when unset. Only some legacy servers look at it
+    user_hostname: Option<String>,
+    verbosity: Verbosity,
+    // Applies to messages we echo back out, like the display of unhandled commands and the server log
+    unknown_tags: TagPolicy,
+    // Disconnect after this long without a message addressed to us; PINGs and other traffic don't count
+    idle_disconnect: Option<Duration>,
+    // Per-target chat logs, e.g. "logs/{network}/{channel}.log". Server messages go to "{channel}" = "server!"
+    log_path_template: Option<String>,
+    nickserv_password: Option<String>,
+    // When our own nick is taken at registration (usually our previous connection lingering),
+    // register under a fallback nick, GHOST the old session and take the nick back
+    ghost_on_collision: bool,
+    // Automatic CTCP replies allowed per nick each minute, so two bots can't keep each other busy
+    ctcp_replies_per_minute: usize,
+    // Send a CTCP VERSION to everyone who joins these channels, sharing the limit above
+    version_query_channels: Vec<String>,
+    // Cap on those queries across all nicks, so a netsplit rejoin doesn't flood us off the server
+    version_queries_per_minute: usize,
+}
+
+// How much of the session the binary prints: Quiet shows only chat, Normal adds channel and
+// server events and any command we don't handle, Verbose adds protocol traffic like PING/PONG
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+impl std::str::FromStr for Verbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "quiet" => Ok(Verbosity::Quiet),
+            "normal" => Ok(Verbosity::Normal),
+            "verbose" => Ok(Verbosity::Verbose),
+            _ => Err(format!("unknown verbosity {:?}, expected quiet, normal or verbose", s)),
+        }
+    }
+}
+
+impl Verbosity {
+    // Whether something rendered at `level` is shown at this verbosity
+    fn shows(self, level: Verbosity) -> bool {
+        self >= level
+    }
+}
+
+macro_rules! render {
+    ($config:expr, $level:expr, $($arg:tt)*) => {
+        if $config.verbosity.shows($level) {
+            emit(format!($($arg)*));
+        }
+    };
+}
+
+// Everything render! lets through ends up here. Tests also collect it, to check what each level shows
+fn emit(line: String) {
+    #[cfg(test)]
+    tests::RENDERED.with(|rendered| rendered.borrow_mut().push(line.clone()));
+    println!("{}", line);
+}
+
+// For gateways relaying web clients: tells the server the real client's host and IP
+struct WebircConfig {
+    password: String,
+    gateway: String,
+    hostname: String,
+    ip: String,
+}
+
+impl WebircConfig {
+    fn validate(&self) -> Result<(), String> {
+        for (field, value) in [("password", &self.password), ("gateway", &self.gateway), ("hostname", &self.hostname)] {
+            if !is_single_token(value) {
+                return Err(format!("invalid webirc {}: must be a single token", field));
+            }
+        }
+        if self.ip.parse::<std::net::IpAddr>().is_err() {
+            return Err(format!("invalid webirc ip {:?}", self.ip));
+        }
+        Ok(())
+    }
 }
 
 impl Default for IrcConfig {
     fn default() -> Self {
         Self {
-            server: "localhost".to_string(),
-            port: 6667,
+            servers: vec![("localhost".to_string(), 6667)],
+            connect_timeout: Duration::from_secs(10),
             nick: "user".to_string(),
             username: "user".to_string(),
             realname: "user".to_string(),
             channels: vec!["#general".to_string()],
+            line_terminator: "\r\n".to_string(),
+            webirc: None,
+            server_password: None,
+            user_hostname: None,
+            verbosity: Verbosity::Normal,
+            unknown_tags: TagPolicy::Passthrough,
+            idle_disconnect: None,
+            log_path_template: None,
+            nickserv_password: None,
+            ghost_on_collision: true,
+            ctcp_replies_per_minute: 3,
+            version_query_channels: Vec::new(),
+            version_queries_per_minute: 10,
         }
     }
 }
 
-struct IrcMessage {
-    prefix: Option<String>,
-    command: String,
-    params: Vec<String>
+impl IrcConfig {
+    // Reads a config file in a small subset of TOML: `key = value` lines with strings, integers,
+    // booleans and single-line arrays of strings, `#` comments, and a [webirc] table. Keys match
+    // the field names, durations are in seconds, and servers are "host:port" strings. Anything
+    // left out keeps its default.
+    fn from_reader(mut reader: impl std::io::Read) -> Result<Self, Box<dyn Error>> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let mut config = IrcConfig::default();
+        let mut webirc: Option<HashMap<String, String>> = None;
+        for (number, line) in text.lines().enumerate() {
+            let at_line = |e: String| format!("config line {}: {}", number + 1, e);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(table) = line.strip_prefix('[') {
+                match table.split_once(']') {
+                    Some(("webirc", rest)) if is_blank_or_comment(rest) => webirc = Some(HashMap::new()),
+                    _ => return Err(at_line(format!("unknown table {}", line)).into()),
+                }
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| at_line("expected key = value".to_string()))?;
+            let key = key.trim();
+            let value = ConfigValue::parse(value.trim()).map_err(at_line)?;
+            match webirc {
+                Some(ref mut table) => {
+                    table.insert(key.to_string(), value.string(key).map_err(at_line)?);
+                }
+                None => config.set(key, value).map_err(at_line)?,
+            }
+        }
+        if let Some(mut table) = webirc {
+            let mut field = |name: &str| table.remove(name).ok_or_else(|| format!("config [webirc] needs {}", name));
+            config.webirc = Some(WebircConfig {
+                password: field("password")?,
+                gateway: field("gateway")?,
+                hostname: field("hostname")?,
+                ip: field("ip")?,
+            });
+            if let Some(unknown) = table.keys().next() {
+                return Err(format!("config [webirc]: unknown key {}", unknown).into());
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: ConfigValue) -> Result<(), String> {
+        match key {
+            "servers" => {
+                self.servers = value.list(key)?.iter()
+                    .map(|server| match server.rsplit_once(':') {
+                        Some((host, port)) => port.parse().map(|port| (host.to_string(), port)).map_err(|_| format!("invalid port in {}", server)),
+                        None => Ok((server.clone(), 6667)),
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "connect_timeout" => self.connect_timeout = Duration::from_secs(value.int(key)?),
+            "nick" => self.nick = value.string(key)?,
+            "username" => self.username = value.string(key)?,
+            "realname" => self.realname = value.string(key)?,
+            "channels" => self.channels = value.list(key)?,
+            "line_terminator" => self.line_terminator = value.string(key)?,
+            "server_password" => self.server_password = Some(value.string(key)?),
+            "user_hostname" => self.user_hostname = Some(value.string(key)?),
+            "verbosity" => self.verbosity = value.string(key)?.parse()?,
+            "unknown_tags" => {
+                self.unknown_tags = match value.string(key)?.as_str() {
+                    "passthrough" => TagPolicy::Passthrough,
+                    "strip" => TagPolicy::Strip,
+                    other => return Err(format!("unknown_tags must be \"passthrough\" or \"strip\", not {:?}", other)),
+                }
+            }
+            "idle_disconnect" => self.idle_disconnect = Some(Duration::from_secs(value.int(key)?)),
+            "log_path_template" => self.log_path_template = Some(value.string(key)?),
+            "nickserv_password" => self.nickserv_password = Some(value.string(key)?),
+            "ghost_on_collision" => self.ghost_on_collision = value.bool(key)?,
+            "ctcp_replies_per_minute" => self.ctcp_replies_per_minute = value.int(key)? as usize,
+            "version_query_channels" => self.version_query_channels = value.list(key)?,
+            "version_queries_per_minute" => self.version_queries_per_minute = value.int(key)? as usize,
+            _ => return Err(format!("unknown key {}", key)),
+        }
+        Ok(())
+    }
 }
 
-impl IrcMessage {
-    fn parse(line: &str) -> Option<Self> {
-        let mut contents = line.split_whitespace();
-        let mut prefix = None;
+#[derive(Debug, PartialEq, Eq)]
+enum ConfigValue {
+    String(String),
+    Int(u64),
+    Bool(bool),
+    List(Vec<String>),
+}
 
-        let first = contents.next()?;
-        let (command, params) = if first.starts_with(':') {
-            prefix = Some(first[1..].to_string());
-            let cmd = contents.next()?.to_string();
-            (cmd, contents.collect::<Vec<_>>())
+impl ConfigValue {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (value, rest) = if s.starts_with('"') {
+            let (string, rest) = parse_config_string(s)?;
+            (ConfigValue::String(string), rest)
+        } else if let Some(mut rest) = s.strip_prefix('[') {
+            let mut items = Vec::new();
+            loop {
+                rest = rest.trim_start();
+                if let Some(after) = rest.strip_prefix(']') {
+                    break (ConfigValue::List(items), after);
+                }
+                let (item, after) = parse_config_string(rest)?;
+                items.push(item);
+                rest = after.trim_start();
+                match rest.strip_prefix(',') {
+                    Some(after) => rest = after,
+                    None if rest.starts_with(']') => {}
+                    None => return Err("expected ',' or ']' in array".to_string()),
+                }
+            }
         } else {
-            (first.to_string(), contents.collect::<Vec<_>>())
+            let end = s.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(s.len());
+            let value = match &s[..end] {
+                "true" => ConfigValue::Bool(true),
+                "false" => ConfigValue::Bool(false),
+                number => ConfigValue::Int(number.parse().map_err(|_| format!("invalid value {}", number))?),
+            };
+            (value, &s[end..])
         };
+        if !is_blank_or_comment(rest) {
+            return Err(format!("unexpected {:?} after value", rest.trim()));
+        }
+        Ok(value)
+    }
 
-        // Handle trailing parameter (" :")
-        if let Some(colon_pos) = line.find(" :") {
-            let (before_colon, after_colon) = line.split_at(colon_pos + 2);
-            let mut new_params: Vec<String> = before_colon.split_whitespace()
-                .skip(if prefix.is_some() { 2 } else { 1 })
-                .map(|s| s.to_string())
-                .collect();
-            new_params.push(after_colon.to_string());
-            return Some(IrcMessage { prefix, command, params: new_params });
+    fn string(self, key: &str) -> Result<String, String> {
+        match self {
+            ConfigValue::String(s) => Ok(s),
+            _ => Err(format!("{} must be a string", key)),
         }
+    }
 
-        Some(IrcMessage {
-            prefix,
-            command,
-            params: params.into_iter().map(|s| s.to_string()).collect(),
-        })
+    fn int(self, key: &str) -> Result<u64, String> {
+        match self {
+            ConfigValue::Int(n) => Ok(n),
+            _ => Err(format!("{} must be a number", key)),
+        }
     }
-}
 
-async fn irc_connect(config: &IrcConfig) -> Result<(TcpStream, &BufReader<OwnedReadHalf>, tokio::net::tcp::OwnedReadHalf), Box<dyn Error>> {
-    let server_addr = format!("{}:{}", config.server, config.port);
-    let stream = TcpStream::connect(&server_addr).await?;
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
+    fn bool(self, key: &str) -> Result<bool, String> {
+        match self {
+            ConfigValue::Bool(b) => Ok(b),
+            _ => Err(format!("{} must be true or false", key)),
+        }
+    }
 
-    let request_message_nick = format!("NICK {}", config.nick);
-    let request_message_user = format!("USER {} 0 :{}", config.username, config.realname);
+    fn list(self, key: &str) -> Result<Vec<String>, String> {
+        match self {
+            ConfigValue::List(items) => Ok(items),
+            _ => Err(format!("{} must be an array of strings", key)),
+        }
+    }
+}
 
-    send_message(&mut writer, &request_message_nick).await?;
-    send_message(&mut writer, &request_message_user).await?;
-    Ok((stream, writer))
+// A basic TOML string starting at the beginning of `s`, returning it unescaped with what follows
+fn parse_config_string(s: &str) -> Result<(String, &str), String> {
+    let mut chars = s.strip_prefix('"').ok_or("expected a string")?.char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &s[i + 2..])),
+            '\\' => string.push(match chars.next() {
+                Some((_, 'n')) => '\n',
+                Some((_, 'r')) => '\r',
+                Some((_, 't')) => '\t',
+                Some((_, '"')) => '"',
+                Some((_, '\\')) => '\\',
+                other => return Err(format!("unknown escape \\{}", other.map(|(_, c)| c).unwrap_or(' '))),
+            }),
+            _ => string.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let config = IrcConfig;
-    let mut (reader, writer) = irc_connect(config).await?;
+fn is_blank_or_comment(s: &str) -> bool {
+    let s = s.trim_start();
+    s.is_empty() || s.starts_with('#')
+}
 
-    send_message(&mut writer, &connection_request1).await?;
-    send_message(&mut writer, &connection_request2).await?;
+// The membership modes (op, voice, ...) a user holds in a channel
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct StatusFlags {
+    modes: Vec<char>,
+}
 
-    let mut input = String::new();
-    loop {
-        input.clear();
-        let bytes_read = reader.read_line(&mut input).await?;
+impl StatusFlags {
+    fn has(&self, mode: char) -> bool {
+        self.modes.contains(&mode)
+    }
 
-        if bytes_read == 0 {
-            println!("Connection closed");
-            break;
+    fn set(&mut self, mode: char, on: bool) {
+        if on && !self.has(mode) {
+            self.modes.push(mode);
+        } else if !on {
+            self.modes.retain(|&m| m != mode);
         }
+    }
+}
 
-        let raw_message = input.trim();
+#[derive(Default)]
+struct ChannelState {
+    topic: Option<String>,
+    // Keyed by case-folded nick
+    members: HashMap<String, StatusFlags>,
+    // Names collected from 353s until the closing 366
+    pending_names: Option<HashMap<String, StatusFlags>>,
+    // Whether the NAMES burst that follows our JOIN has finished
+    names_synced: bool,
+}
+
+impl ChannelState {
+    // Membership changes also apply to a NAMES burst still arriving, which may have listed the user already
+    fn remove_member(&mut self, member: &str) {
+        self.members.remove(member);
+        if let Some(names) = self.pending_names.as_mut() {
+            names.remove(member);
+        }
+    }
 
-        if let Some(parsed_message) = IrcMessage::parse(raw_message) {
-            if let Some(response) = handle_message(&parsed_message, &config).await {
-                send_message(&mut writer, &response).await?;
+    fn rename_member(&mut self, old: &str, new: &str) {
+        if let Some(status) = self.members.remove(old) {
+            self.members.insert(new.to_string(), status);
+        }
+        if let Some(names) = self.pending_names.as_mut() {
+            if let Some(status) = names.remove(old) {
+                names.insert(new.to_string(), status);
             }
-        } else {
-            println!("Failed to parse message: {}", raw_message);
         }
     }
+}
 
-    Ok(())
+// The CHANMODES groups A-D (list masks, always a param, param only when set, no param),
+// plus the PREFIX modes like op and voice, which always take a nick
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ModeKind {
+    List,
+    Always,
+    OnSet,
+    NoParam,
+    Prefix,
 }
 
-async fn send_message(writer: &mut tokio::net::tcp::OwnedWriteHalf, message: &str) -> Result<(), Box<dyn Error>> {
-    writer.write_all(format!("{}\r\n", message).as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
+struct ModeSpec {
+    kinds: HashMap<char, ModeKind>,
+    // (mode, symbol) pairs from PREFIX, highest rank first
+    prefixes: Vec<(char, char)>,
 }
 
-fn handle_numeric_reply(code: &str, message: &IrcMessage) {
-    match code {
-        "001" => println!("Connected to server"),
-        "353" => {
-            if message.params.len() >= 4 {
-                let channel = &message.params[2];
-                let users = &message.params[3];
-                println!("Users in {}: {}", channel, users);
+impl Default for ModeSpec {
+    fn default() -> Self {
+        // RFC 2811 modes, used until the server tells us otherwise
+        let mut spec = Self {
+            kinds: HashMap::new(),
+            prefixes: Vec::new(),
+        };
+        spec.set_chanmodes("beI,k,l,aimnpqrst");
+        spec.set_prefix("(ov)@+");
+        spec
+    }
+}
+
+impl ModeSpec {
+    fn set_chanmodes(&mut self, value: &str) {
+        let groups = [ModeKind::List, ModeKind::Always, ModeKind::OnSet, ModeKind::NoParam];
+        self.kinds.retain(|_, kind| *kind == ModeKind::Prefix);
+        for (modes, kind) in value.split(',').zip(groups) {
+            for mode in modes.chars() {
+                self.kinds.entry(mode).or_insert(kind);
             }
-        },
-        "366" => {
-            if message.params.len() >= 2 {
-                println!("End of names list for {}", message.params[1]);
+        }
+    }
+
+    fn set_prefix(&mut self, value: &str) {
+        self.kinds.retain(|_, kind| *kind != ModeKind::Prefix);
+        self.prefixes.clear();
+        if let Some((modes, symbols)) = value.strip_prefix('(').and_then(|v| v.split_once(')')) {
+            for (mode, symbol) in modes.chars().zip(symbols.chars()) {
+                self.kinds.insert(mode, ModeKind::Prefix);
+                self.prefixes.push((mode, symbol));
             }
-        },
-        "372" => {
-            if let Some(msg) = message.params.last() {
-                println!("{}", msg);
+        }
+    }
+
+    // 004 only says which channel modes exist and which of those take a parameter. Use it
+    // for modes we know nothing about, until CHANMODES in 005 gives the proper grouping.
+    fn apply_myinfo(&mut self, channel_modes: &str, param_modes: &str) {
+        for mode in channel_modes.chars() {
+            let kind = if param_modes.contains(mode) { ModeKind::Always } else { ModeKind::NoParam };
+            self.kinds.entry(mode).or_insert(kind);
+        }
+    }
+
+    fn kind(&self, mode: char) -> Option<ModeKind> {
+        self.kinds.get(&mode).copied()
+    }
+
+    fn takes_param(&self, mode: char, adding: bool) -> bool {
+        match self.kind(mode) {
+            Some(ModeKind::List) | Some(ModeKind::Always) | Some(ModeKind::Prefix) => true,
+            Some(ModeKind::OnSet) => adding,
+            Some(ModeKind::NoParam) | None => false,
+        }
+    }
+
+    fn takes_nick(&self, mode: char) -> bool {
+        self.kind(mode) == Some(ModeKind::Prefix)
+    }
+
+    fn takes_mask(&self, mode: char) -> bool {
+        self.kind(mode) == Some(ModeKind::List)
+    }
+
+    // Split a NAMES entry like "@+nick" into the nick and the modes its symbols stand for
+    fn parse_names_entry<'a>(&self, entry: &'a str) -> (&'a str, StatusFlags) {
+        let mut status = StatusFlags::default();
+        let nick = entry.trim_start_matches(|c| {
+            match self.prefixes.iter().find(|&&(_, symbol)| symbol == c) {
+                Some(&(mode, _)) => {
+                    status.set(mode, true);
+                    true
+                }
+                None => false,
             }
-        },
-        "375" => println!("--- Message of the Day ---"),
-        "376" => println!("--- End of MOTD ---"),
-        _ => {
-            // The above have important information. For the rest, a minimal display suffices
-            if !message.params.is_empty() {
-                if let Some(msg) = message.params.last() {
-                    if msg.len() > 1 {
-                        println!("{}", msg);
-                    }
+        });
+        (nick, status)
+    }
+
+    // The symbol for the highest-ranked mode in `status`, e.g. '@' for an op who is also voiced
+    fn highest_symbol(&self, status: &StatusFlags) -> Option<char> {
+        self.prefixes.iter().find(|&&(mode, _)| status.has(mode)).map(|&(_, symbol)| symbol)
+    }
+
+    // Pair each mode character in `modes` with its argument, if it takes one
+    fn parse_changes(&self, modes: &str, args: &[String]) -> Vec<(bool, char, Option<String>)> {
+        let mut args = args.iter();
+        let mut adding = true;
+        let mut changes = Vec::new();
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ => {
+                    let arg = if self.takes_param(mode, adding) { args.next().cloned() } else { None };
+                    changes.push((adding, mode, arg));
                 }
             }
         }
+        changes
     }
 }
 
-async fn handle_message(message: &IrcMessage, config: &IrcConfig) -> Option<String> {
-    match message.command.as_str() {
-        "PING" => {
-            if let Some(server) = message.params.first() {
-                println!("< PING {}", server);
-                println!("> PONG {}", server);
-                Some(format!("PONG {}", server))
-            } else {
-                None
-            }
-        }
-        "001" => {
-            handle_numeric_reply("001", message);
-            if let Some(channel) = config.channels.first() {
-                Some(format!("JOIN {}", channel))
-            } else {
-                None
-            }
+// IRCv3 standard replies: FAIL/WARN/NOTE <command> <code> [<context>...] :<description>
+struct StandardReply {
+    kind: String,
+    command: String,
+    code: String,
+    context: Vec<String>,
+    description: String,
+}
+
+impl StandardReply {
+    fn from_message(message: &IrcMessage) -> Option<Self> {
+        let (description, rest) = message.params.split_last()?;
+        let (command, rest) = rest.split_first()?;
+        let (code, context) = rest.split_first()?;
+        Some(Self {
+            kind: message.command.clone(),
+            command: command.clone(),
+            code: code.clone(),
+            context: context.to_vec(),
+            description: description.clone(),
+        })
+    }
+}
+
+// 351 RPL_VERSION: <version>[.<debuglevel>] <server> :<comments>
+struct ServerVersion {
+    version: String,
+    server: String,
+    comments: String,
+}
+
+// Gathered from the 256-259 RPL_ADMIN* replies to ADMIN
+#[derive(Default)]
+struct AdminInfo {
+    server: Option<String>,
+    location: Vec<String>,
+    email: Option<String>,
+}
+
+// The log for server notices and numerics. Nicks can't contain '!', so no query log can share it
+const SERVER_LOG: &str = "server!";
+
+// Open log files are kept for reuse, up to this many; the oldest is closed to make room
+const MAX_OPEN_LOGS: usize = 32;
+
+struct ChatLog {
+    template: String,
+    network: String,
+    files: HashMap<PathBuf, File>,
+    // Paths in `files`, oldest first
+    open_order: VecDeque<PathBuf>,
+}
+
+impl ChatLog {
+    fn new(template: &str, network: &str) -> Self {
+        Self {
+            template: template.to_string(),
+            network: sanitize_log_name(network),
+            files: HashMap::new(),
+            open_order: VecDeque::new(),
         }
-        "PRIVMSG" => {
-            if message.params.len() >= 2 {
-                let channel = &message.params[0];
-                let msg = &message.params[1];
-                if let Some(ref prefix) = message.prefix {
-                    let nick = prefix.split('!').next().unwrap_or(prefix);
-                    println!("[{}] <{}> {}", channel, nick, msg);
+    }
+
+    // `target` should already be case-folded so "#Chan" and "#chan" share a file
+    fn write(&mut self, target: &str, line: &str) {
+        let path = PathBuf::from(self.template
+            .replace("{network}", &self.network)
+            .replace("{channel}", &sanitize_log_name(target)));
+        if !self.files.contains_key(&path) {
+            let opened = path.parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| OpenOptions::new().create(true).append(true).open(&path));
+            match opened {
+                Ok(file) => {
+                    if self.open_order.len() >= MAX_OPEN_LOGS {
+                        if let Some(oldest) = self.open_order.pop_front() {
+                            self.files.remove(&oldest);
+                        }
+                    }
+                    self.files.insert(path.clone(), file);
+                    self.open_order.push_back(path.clone());
+                }
+                Err(e) => {
+                    eprintln!("Failed to open log {}: {}", path.display(), e);
+                    return;
                 }
             }
-            None
         }
-        "JOIN" => {
-            if let Some(channel) = message.params.first() {
-                if let Some(ref prefix) = message.prefix {
-                    let nick = prefix.split('!').next().unwrap_or(prefix);
-                    println!("* {} joined {}", nick, channel);
-                }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if let Some(file) = self.files.get_mut(&path) {
+            if let Err(e) = writeln!(file, "{} {}", timestamp, line) {
+                eprintln!("Failed to write log {}: {}", path.display(), e);
             }
-            None
         }
-        "PART" => {
-            if let Some(channel) = message.params.first() {
-                if let Some(ref prefix) = message.prefix {
-                    let nick = prefix.split('!').next().unwrap_or(prefix);
-                    println!("* {} left {}", nick, channel);
-                }
+    }
+}
+
+// Channel names may contain anything but spaces, commas and BEL, so keep only what is safe in a file name
+fn sanitize_log_name(name: &str) -> String {
+    let sanitized: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '#' | '&' | '+' | '!' | '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    // Never let a name resolve to the current or parent directory
+    if sanitized.chars().all(|c| c == '.') {
+        sanitized.replace('.', "_")
+    } else {
+        sanitized
+    }
+}
+
+// A summary of the session as learned during registration, kept up to date as it changes
+#[derive(Clone, Default)]
+struct ConnectionInfo {
+    server_name: Option<String>,
+    server_host: Option<String>,
+    server_created: Option<String>,
+    server_version: Option<String>,
+    user_modes: Option<String>,
+    channel_modes: Option<String>,
+    nick: String,
+    user: Option<String>,
+    host: Option<String>,
+    isupport: HashMap<String, String>,
+}
+
+// Everything that shapes how this session behaves, for diagnostics. The client doesn't negotiate
+// capabilities, SASL or TLS, so those don't appear here
+struct Features {
+    server: Option<(String, u16)>,
+    isupport: Vec<String>,
+    casemapping: String,
+    webirc: bool,
+    ghost_on_collision: bool,
+    idle_disconnect: Option<Duration>,
+    chat_logging: bool,
+    ctcp_replies_per_minute: usize,
+    version_query_channels: Vec<String>,
+    version_queries_per_minute: usize,
+}
+
+impl std::fmt::Display for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.server {
+            Some((ref host, port)) => writeln!(f, "Server: {}:{}", host, port)?,
+            None => writeln!(f, "Server: not connected")?,
+        }
+        writeln!(f, "ISUPPORT: {}", self.isupport.join(" "))?;
+        writeln!(f, "Casemapping: {}", self.casemapping)?;
+        writeln!(f, "WEBIRC: {}", if self.webirc { "on" } else { "off" })?;
+        writeln!(f, "Ghost on nick collision: {}", if self.ghost_on_collision { "on" } else { "off" })?;
+        match self.idle_disconnect {
+            Some(limit) => writeln!(f, "Idle disconnect: after {:?}", limit)?,
+            None => writeln!(f, "Idle disconnect: off")?,
+        }
+        writeln!(f, "Chat logging: {}", if self.chat_logging { "on" } else { "off" })?;
+        writeln!(f, "CTCP replies per nick per minute: {}", self.ctcp_replies_per_minute)?;
+        write!(f, "VERSION queries on join: {}", if self.version_query_channels.is_empty() {
+            "off".to_string()
+        } else {
+            format!("{} (at most {} a minute)", self.version_query_channels.join(" "), self.version_queries_per_minute)
+        })
+    }
+}
+
+// Taking our nick back from a lingering session of ours via NickServ
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Ghost {
+    Idle,
+    // Our nick was taken at registration; GHOST it once registered
+    Pending,
+    // Waiting for NickServ to answer the GHOST
+    Sent,
+    // NICK sent to take it back; IDENTIFY once the server confirms the change
+    Reclaiming,
+}
+
+struct IrcState {
+    info: ConnectionInfo,
+    // Which entry of config.servers we're connected to, None when replaying
+    active_server: Option<(String, u16)>,
+    channels: HashMap<String, ChannelState>,
+    mode_spec: ModeSpec,
+    // Set on 001; before that a rejected nick means we must pick another to finish registering
+    registered: bool,
+    ghost: Ghost,
+    // The server refused a nick of ours as erroneous before we registered, most likely for
+    // length. NICKLEN only arrives after registration, so fallbacks stay within the RFC's 9
+    nick_rejected: bool,
+    ctcp_replies: HashMap<String, VecDeque<Instant>>,
+    // When each recent VERSION-on-join query went out, for version_queries_per_minute
+    version_queries: VecDeque<Instant>,
+    last_activity: Instant,
+    chat_log: Option<ChatLog>,
+    // Replies to our CTCP VERSION queries, keyed by case-folded nick
+    client_versions: HashMap<String, String>,
+    // Filled in by 256-258 and shown once 259 ends the reply
+    admin_info: Option<AdminInfo>,
+    // The last 351 reply to VERSION
+    server_version: Option<ServerVersion>,
+    // draft/metadata values keyed by (case-folded target, key)
+    metadata: HashMap<(String, String), String>,
+}
+
+impl IrcState {
+    fn new(config: &IrcConfig) -> Self {
+        Self {
+            info: ConnectionInfo {
+                nick: config.nick.clone(),
+                ..ConnectionInfo::default()
+            },
+            active_server: None,
+            channels: HashMap::new(),
+            mode_spec: ModeSpec::default(),
+            registered: false,
+            ghost: Ghost::Idle,
+            nick_rejected: false,
+            ctcp_replies: HashMap::new(),
+            version_queries: VecDeque::new(),
+            last_activity: Instant::now(),
+            client_versions: HashMap::new(),
+            admin_info: None,
+            server_version: None,
+            metadata: HashMap::new(),
+            chat_log: config.log_path_template.as_deref().map(|template| {
+                // Named after the first configured server, so falling over to another one keeps the same log files
+                let network = config.servers.first().map(|(host, _)| host.as_str()).unwrap_or("unknown");
+                ChatLog::new(template, network)
+            }),
+        }
+    }
+
+    // Lowercase a nick or channel name the way the server compares them (ISUPPORT CASEMAPPING)
+    fn fold_case(&self, name: &str) -> String {
+        let casemapping = self.info.isupport.get("CASEMAPPING").map(String::as_str).unwrap_or("rfc1459");
+        name.chars()
+            .map(|c| match (casemapping, c) {
+                ("ascii", _) => c.to_ascii_lowercase(),
+                (_, '[') => '{',
+                (_, ']') => '}',
+                (_, '\\') => '|',
+                ("rfc1459", '^') => '~',
+                _ => c.to_ascii_lowercase(),
+            })
+            .collect()
+    }
+
+    // Always compares against the nick as of the message being handled, so an echo that
+    // arrives right after our own NICK change is still recognised
+    fn is_self(&self, nick: &str) -> bool {
+        self.fold_case(nick) == self.fold_case(&self.info.nick)
+    }
+
+    // Check a nick against the server's NICKLEN before sending it, rather than waiting for a 432.
+    // Until 005 arrives we don't know the limit, and many servers allow well over the RFC's 9,
+    // so nothing is rejected then unless the server has already refused a nick for its length.
+    fn validate_nick(&self, nick: &str) -> Result<(), String> {
+        match self.max_nick_len() {
+            Some(max) if nick.chars().count() > max => Err(format!("nick {} is longer than the server's limit of {}", nick, max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn max_nick_len(&self) -> Option<usize> {
+        let nicklen = self.info.isupport.get("NICKLEN").and_then(|len| len.parse::<usize>().ok());
+        nicklen.or(if self.nick_rejected { Some(9) } else { None })
+    }
+
+    // The nick to try after `attempted` was refused: an underscore appended, or once that no
+    // longer fits, a digit in the last position that counts up with each retry
+    fn fallback_nick(&self, attempted: &str) -> String {
+        let chars: Vec<char> = attempted.chars().collect();
+        match self.max_nick_len() {
+            Some(max) if chars.len() >= max => {
+                let keep = max.saturating_sub(1);
+                let mut fallback: String = chars[..keep].iter().collect();
+                fallback.push(match chars.get(keep) {
+                    Some(&c @ '0'..='8') => (c as u8 + 1) as char,
+                    _ => '0',
+                });
+                fallback
             }
-            None
+            _ => format!("{}_", attempted),
         }
-        _ => {
-            if message.command.chars().all(|c| c.is_ascii_digit()) {
-                handle_numeric_reply(&message.command, message);
-            } else {
-                println!("< {}", message.command);
+    }
+
+    fn channel_mut(&mut self, channel: &str) -> Option<&mut ChannelState> {
+        let key = self.fold_case(channel);
+        self.channels.get_mut(&key)
+    }
+
+    fn member_status(&self, channel: &str, nick: &str) -> Option<&StatusFlags> {
+        self.channels.get(&self.fold_case(channel))?.members.get(&self.fold_case(nick))
+    }
+
+    // Convenience checks for permission handling; false when either the channel or nick isn't tracked.
+    // Nothing gates on permissions yet, so for now only the tests use them to check member tracking
+    #[cfg(test)]
+    fn is_op(&self, channel: &str, nick: &str) -> bool {
+        self.member_status(channel, nick).is_some_and(|status| status.has('o'))
+    }
+
+    #[cfg(test)]
+    fn is_voiced(&self, channel: &str, nick: &str) -> bool {
+        self.member_status(channel, nick).is_some_and(|status| status.has('v'))
+    }
+
+    // Numerics normally start with our nick (or "*" before registration), but not every server
+    // sends it. Strip it when present so handlers can index the params the same way regardless.
+    fn numeric_params<'a>(&self, message: &'a IrcMessage) -> &'a [String] {
+        match message.params.split_first() {
+            Some((first, rest)) if first == "*" || self.is_self(first) => rest,
+            _ => &message.params,
+        }
+    }
+
+    fn is_channel(&self, target: &str) -> bool {
+        let chantypes = self.info.isupport.get("CHANTYPES").map(String::as_str).unwrap_or("#&");
+        target.starts_with(|c| chantypes.contains(c))
+    }
+
+    // A missing value means the key was unset. Returns the value it replaced
+    fn set_metadata(&mut self, target: &str, key: &str, value: Option<&str>) -> Option<String> {
+        let entry = (self.fold_case(target), key.to_string());
+        match value {
+            Some(value) => self.metadata.insert(entry, value.to_string()),
+            None => self.metadata.remove(&entry),
+        }
+    }
+
+    // Private messages are logged under the other user's nick, like a query window
+    fn log(&mut self, target: &str, line: &str) {
+        let target = self.fold_case(target);
+        if let Some(ref mut chat_log) = self.chat_log {
+            chat_log.write(&target, line);
+        }
+    }
+
+    // A private message, or a channel message mentioning our nick, counts as activity for idle_disconnect
+    fn note_activity(&mut self, target: &str, msg: &str) {
+        if !self.is_channel(target) || mentions(&self.fold_case(msg), &self.fold_case(&self.info.nick)) {
+            self.last_activity = Instant::now();
+        }
+    }
+
+    // Returns false once `nick` has had `limit` CTCP replies from us within the last minute
+    fn allow_ctcp_reply(&mut self, nick: &str, limit: usize) -> bool {
+        let key = self.fold_case(nick);
+        allow_within_minute(self.ctcp_replies.entry(key).or_default(), limit)
+    }
+
+    fn connection_info(&self) -> ConnectionInfo {
+        self.info.clone()
+    }
+
+    fn features(&self, config: &IrcConfig) -> Features {
+        let mut isupport: Vec<String> = self.info.isupport.iter()
+            .map(|(name, value)| if value.is_empty() { name.clone() } else { format!("{}={}", name, value) })
+            .collect();
+        isupport.sort();
+        Features {
+            server: self.active_server.clone(),
+            isupport,
+            casemapping: self.info.isupport.get("CASEMAPPING").cloned().unwrap_or_else(|| "rfc1459".to_string()),
+            webirc: config.webirc.is_some(),
+            ghost_on_collision: config.ghost_on_collision && config.nickserv_password.is_some(),
+            idle_disconnect: config.idle_disconnect,
+            chat_logging: self.chat_log.is_some(),
+            ctcp_replies_per_minute: config.ctcp_replies_per_minute,
+            version_query_channels: config.version_query_channels.clone(),
+            version_queries_per_minute: config.version_queries_per_minute,
+        }
+    }
+
+    fn mode_spec(&self) -> &ModeSpec {
+        &self.mode_spec
+    }
+
+    fn apply_isupport(&mut self, tokens: &[String]) {
+        for token in tokens {
+            if let Some(name) = token.strip_prefix('-') {
+                self.info.isupport.remove(name);
+                continue;
             }
-            None
+            let (name, value) = token.split_once('=').unwrap_or((token, ""));
+            match name {
+                "CHANMODES" => self.mode_spec.set_chanmodes(value),
+                "PREFIX" => self.mode_spec.set_prefix(value),
+                _ => {}
+            }
+            self.info.isupport.insert(name.to_string(), value.to_string());
         }
     }
 }
+
+// What to do with tags we don't recognise (vendor tags, newer drafts) when writing a message back out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TagPolicy {
+    Passthrough,
+    Strip,
+}
+
+// Tags this client understands or that are standard IRCv3
+const KNOWN_TAGS: &[&str] = &["account", "batch", "label", "msgid", "time", "+draft/channel-context", "+draft/delete"];
+
+struct IrcMessage {
+    tags: HashMap<String, String>,
+    prefix: Option<String>,
+    command: String,
+    params: Vec<String>,
+    // Set when a bare CR was removed from the trailing param
+    stripped_cr: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ParseErrorKind {
+    MissingCommand,
+    InvalidCommand,
+    TooLong,
+    InvalidUtf8,
+}
+
+#[derive(Debug)]
+struct ParseError {
+    kind: ParseErrorKind,
+    // Byte offset into the line where the problem was found
+    offset: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.kind {
+            ParseErrorKind::MissingCommand => "missing command",
+            ParseErrorKind::InvalidCommand => "command is neither a word nor a 3-digit numeric",
+            ParseErrorKind::TooLong => "line is longer than the protocol allows",
+            ParseErrorKind::InvalidUtf8 => "invalid UTF-8",
+        };
+        write!(f, "{} at byte {}", reason, self.offset)
+    }
+}
+
+impl Error for ParseError {}
+
+// RFC 1459 allows 512 bytes including the CRLF, and IRCv3 allows a further 8191 bytes of tags
+const MAX_LINE_LEN: usize = 510;
+const MAX_TAGS_LEN: usize = 8191;
+
+impl IrcMessage {
+    // Lenient variant, accepting anything with a command like the original parser did. Many
+    // servers send lines longer than the RFC allows, and those still need handling
+    fn parse(line: &str) -> Option<Self> {
+        Self::parse_checked(line, false).ok()
+    }
+
+    // Strict variant that also rejects over-long lines and malformed commands
+    fn try_parse(line: &str) -> Result<Self, ParseError> {
+        Self::parse_checked(line, true)
+    }
+
+    fn parse_checked(full_line: &str, strict: bool) -> Result<Self, ParseError> {
+        let error = |kind, offset| ParseError { kind, offset };
+
+        // IRCv3 message tags: "@key=value;key2 :prefix COMMAND ..."
+        let (tags, line) = match full_line.strip_prefix('@') {
+            Some(rest) => {
+                let (raw_tags, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                if strict && raw_tags.len() > MAX_TAGS_LEN {
+                    return Err(error(ParseErrorKind::TooLong, 1 + MAX_TAGS_LEN));
+                }
+                (parse_tags(raw_tags), rest.trim_start())
+            }
+            None => (HashMap::new(), full_line),
+        };
+        let body_start = full_line.len() - line.len();
+        if strict && line.len() > MAX_LINE_LEN {
+            return Err(error(ParseErrorKind::TooLong, body_start + MAX_LINE_LEN));
+        }
+
+        let mut contents = line.split_whitespace();
+        let mut prefix = None;
+
+        let missing_command = || error(ParseErrorKind::MissingCommand, full_line.len());
+        let first = contents.next().ok_or_else(missing_command)?;
+        let (command, params) = if let Some(stripped) = first.strip_prefix(':') {
+            prefix = Some(stripped.to_string());
+            let cmd = contents.next().ok_or_else(missing_command)?;
+            (cmd, contents.collect::<Vec<_>>())
+        } else {
+            (first, contents.collect::<Vec<_>>())
+        };
+
+        let is_numeric = command.len() == 3 && command.chars().all(|c| c.is_ascii_digit());
+        if strict && !is_numeric && !command.chars().all(|c| c.is_ascii_alphabetic()) {
+            let offset = command.as_ptr() as usize - full_line.as_ptr() as usize;
+            return Err(error(ParseErrorKind::InvalidCommand, offset));
+        }
+        let command = command.to_string();
+
+        // Handle trailing parameter (" :"). Runs of spaces between middle params collapse, but
+        // everything after the colon is kept exactly, including repeated or trailing spaces
+        if let Some(colon_pos) = line.find(" :") {
+            let (before_colon, after_colon) = line.split_at(colon_pos);
+            let mut new_params: Vec<String> = before_colon.split_whitespace()
+                .skip(if prefix.is_some() { 2 } else { 1 })
+                .map(|s| s.to_string())
+                .collect();
+            // A CR inside the trailing param can't be part of the framing, and left in it can
+            // rewrite what a terminal or log shows, so drop it and let the caller know
+            let trailing = &after_colon[2..];
+            let stripped_cr = trailing.contains('\r');
+            new_params.push(trailing.replace('\r', ""));
+            return Ok(IrcMessage { tags, prefix, command, params: new_params, stripped_cr });
+        }
+
+        Ok(IrcMessage {
+            tags,
+            prefix,
+            command,
+            params: params.into_iter().map(|s| s.to_string()).collect(),
+            stripped_cr: false,
+        })
+    }
+
+    // The nick part of a nick!user@host prefix, or the whole prefix when it's a server name
+    fn source_nick(&self) -> Option<&str> {
+        let prefix = self.prefix.as_deref()?;
+        prefix.split('!').next()
+    }
+
+    // Every tag is kept on parse; the policy decides whether ones we don't understand are written back out
+    fn to_wire(&self, policy: TagPolicy) -> String {
+        let mut line = String::new();
+        let mut tags: Vec<_> = self.tags.iter()
+            .filter(|(key, _)| policy == TagPolicy::Passthrough || KNOWN_TAGS.contains(&key.as_str()))
+            .collect();
+        if !tags.is_empty() {
+            // Sorted so the same message always serialises the same way
+            tags.sort();
+            line.push('@');
+            for (i, (key, value)) in tags.into_iter().enumerate() {
+                if i > 0 {
+                    line.push(';');
+                }
+                line.push_str(key);
+                if !value.is_empty() {
+                    line.push('=');
+                    line.push_str(&escape_tag_value(value));
+                }
+            }
+            line.push(' ');
+        }
+        if let Some(ref prefix) = self.prefix {
+            line.push(':');
+            line.push_str(prefix);
+            line.push(' ');
+        }
+        line.push_str(&self.command);
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                line.push(' ');
+                line.push_str(param);
+            }
+            // The last param needs the trailing form unless it's a plain single token
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                line.push_str(" :");
+            } else {
+                line.push(' ');
+            }
+            line.push_str(last);
+        }
+        line
+    }
+}
+
+fn parse_tags(raw: &str) -> HashMap<String, String> {
+    raw.split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+            (key.to_string(), unescape_tag_value(value))
+        })
+        .collect()
+}
+
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        // A lone trailing backslash is dropped, and unknown escapes just lose the backslash
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+// Unescaped tag values can contain CR, LF and other control characters, which would let the
+// sender rewrite what a terminal or log shows, so drop them before displaying a value
+fn printable_tag_value(value: &str) -> String {
+    value.chars().filter(|c| !c.is_control()).collect()
+}
+
+// Try each server in turn and keep the first that accepts the connection. A server that drops
+// packets would otherwise hold us for the OS's SYN timeout, which can be minutes
+async fn connect_any(config: &IrcConfig) -> Result<(TcpStream, (String, u16)), Box<dyn Error>> {
+    let timeout = config.connect_timeout;
+    let mut last_error: Option<Box<dyn Error>> = None;
+    for (host, port) in &config.servers {
+        let server_addr = format!("{}:{}", host, port);
+        let error: Box<dyn Error> = match tokio::time::timeout(timeout, TcpStream::connect(&server_addr)).await {
+            Ok(Ok(stream)) => return Ok((stream, (host.clone(), *port))),
+            Ok(Err(e)) => e.into(),
+            Err(_) => format!("no answer within {:?}", timeout).into(),
+        };
+        render!(config, Verbosity::Normal, "Couldn't connect to {}:{}: {}", host, port, error);
+        last_error = Some(error);
+    }
+    Err(last_error.unwrap_or_else(|| "no servers configured".into()))
+}
+
+// The lines that open a session, in the order the server needs them
+fn registration_lines(config: &IrcConfig) -> Result<Vec<String>, String> {
+    // The hostname is a middle parameter of USER, so it has to be a single token
+    let user_hostname = config.user_hostname.as_deref().unwrap_or("*");
+    if !is_single_token(user_hostname) {
+        return Err(format!("invalid user_hostname {:?}: must be a single token", user_hostname));
+    }
+    let mut lines = Vec::new();
+
+    // WEBIRC has to come before anything else for the server to apply the real client's address
+    if let Some(ref webirc) = config.webirc {
+        webirc.validate()?;
+        // An IPv6 address like ::1 would start a trailing param, so send it as the equivalent 0::1
+        let ip = if webirc.ip.starts_with(':') { format!("0{}", webirc.ip) } else { webirc.ip.clone() };
+        lines.push(format!("WEBIRC {} {} {} {}", webirc.password, webirc.gateway, webirc.hostname, ip));
+    }
+
+    // PASS must precede NICK/USER
+    if let Some(ref password) = config.server_password {
+        lines.push(format!("PASS {}", password));
+    }
+
+    lines.push(format!("NICK {}", config.nick));
+    lines.push(format!("USER {} 0 {} :{}", config.username, user_hostname, config.realname));
+    Ok(lines)
+}
+
+// A line we send as it should be shown, with any password hidden
+fn redacted(line: &str) -> String {
+    match line.split_once(' ') {
+        Some(("PASS", _)) => "PASS ****".to_string(),
+        Some(("WEBIRC", rest)) => match rest.split_once(' ') {
+            Some((_, rest)) => format!("WEBIRC **** {}", rest),
+            None => "WEBIRC ****".to_string(),
+        },
+        _ => line.to_string(),
+    }
+}
+
+async fn irc_connect(config: &IrcConfig) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf, (String, u16)), Box<dyn Error>> {
+    let lines = registration_lines(config)?;
+    if config.line_terminator != "\r\n" && config.line_terminator != "\n" {
+        return Err(format!("invalid line_terminator {:?}: must be \"\\r\\n\" or \"\\n\"", config.line_terminator).into());
+    }
+
+    let (stream, active) = connect_any(config).await?;
+    render!(config, Verbosity::Normal, "Connected to {}:{}", active.0, active.1);
+    let (reader, mut writer) = stream.into_split();
+    let reader = BufReader::new(reader);
+
+    for line in &lines {
+        render!(config, Verbosity::Verbose, "> {}", redacted(line));
+        send_message(&mut writer, line, &config.line_terminator).await?;
+    }
+    Ok((reader, writer, active))
+}
+
+// Whether `nick` appears in `msg` as a whole word, so "dave" isn't mentioned by "daves"
+fn mentions(msg: &str, nick: &str) -> bool {
+    let is_nick_char = |c: char| c.is_alphanumeric() || "[]\\`_^{|}-".contains(c);
+    !nick.is_empty() && msg.match_indices(nick).any(|(start, _)| {
+        let before = msg[..start].chars().next_back();
+        let after = msg[start + nick.len()..].chars().next();
+        !before.is_some_and(is_nick_char) && !after.is_some_and(is_nick_char)
+    })
+}
+
+// Whether `value` can be sent as a middle parameter
+fn is_single_token(value: &str) -> bool {
+    !value.is_empty() && !value.starts_with(':') && !value.contains(char::is_whitespace)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut config_path = None;
+    let mut verbosity = None;
+    let mut strip_unknown_tags = false;
+    let mut replay_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => config_path = Some(args.next().ok_or("--config needs a file, or - for stdin")?),
+            "-q" | "--quiet" => verbosity = Some(Verbosity::Quiet),
+            "-v" | "--verbose" => verbosity = Some(Verbosity::Verbose),
+            "--strip-unknown-tags" => strip_unknown_tags = true,
+            "--replay" => replay_path = Some(args.next().ok_or("--replay needs a file of raw server lines")?),
+            _ => return Err(format!("unknown argument {:?}", arg).into()),
+        }
+    }
+
+    // "-" reads the config from stdin, so secrets can be piped in rather than written to disk
+    let mut config = match config_path.as_deref() {
+        Some("-") => IrcConfig::from_reader(std::io::stdin().lock())?,
+        Some(path) => IrcConfig::from_reader(File::open(path)?)?,
+        None => IrcConfig::default(),
+    };
+    // IRC_VERBOSITY overrides the config file, and -q/-v on the command line override both
+    if let Ok(level) = std::env::var("IRC_VERBOSITY") {
+        config.verbosity = level.parse()?;
+    }
+    if let Some(level) = verbosity {
+        config.verbosity = level;
+    }
+    if strip_unknown_tags {
+        config.unknown_tags = TagPolicy::Strip;
+    }
+
+    if let Some(path) = replay_path {
+        let log = std::fs::read(path)?;
+        let mut state = IrcState::new(&config);
+        for response in replay(&log, &config, &mut state).await {
+            println!("> {}", response);
+        }
+        return Ok(());
+    }
+
+    let (reader, writer, active) = irc_connect(&config).await?;
+    let mut state = IrcState::new(&config);
+    state.active_server = Some(active);
+    run_session(reader, writer, &config, &mut state).await
+}
+
+// Handle server lines until the connection closes or idle_disconnect runs out
+async fn run_session<R, W>(mut reader: R, mut writer: W, config: &IrcConfig, state: &mut IrcState) -> Result<(), Box<dyn Error>>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Set once a write fails. The server may have stopped reading while still sending, so we keep
+    // reading whatever it has left for us, but stop trying to send, and disconnect at EOF
+    let mut draining = false;
+    let mut input = Vec::new();
+    loop {
+        input.clear();
+        let bytes_read = match config.idle_disconnect {
+            Some(limit) => {
+                let remaining = limit.saturating_sub(state.last_activity.elapsed());
+                match tokio::time::timeout(remaining, reader.read_until(b'\n', &mut input)).await {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        render!(config, Verbosity::Normal, "Nothing addressed to us for {:?}, disconnecting", limit);
+                        if !draining {
+                            send_message(&mut writer, "QUIT :Idle", &config.line_terminator).await?;
+                        }
+                        break;
+                    }
+                }
+            }
+            None => reader.read_until(b'\n', &mut input).await?,
+        };
+
+        if bytes_read == 0 {
+            render!(config, Verbosity::Normal, "Connection closed");
+            break;
+        }
+
+        for response in process_line(&input, config, state).await {
+            if draining {
+                render!(config, Verbosity::Verbose, "Not sending {}, connection is closing", response);
+                continue;
+            }
+            if let Err(e) = send_message(&mut writer, &response, &config.line_terminator).await {
+                render!(config, Verbosity::Normal, "Failed to send ({}), reading remaining messages before disconnecting", e);
+                draining = true;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Servers relay whatever bytes clients send, and not every client uses UTF-8. Anything that
+// doesn't decode is replaced rather than dropping the whole line.
+fn decode_line(raw: &[u8]) -> (Cow<'_, str>, Option<ParseError>) {
+    let end = raw.iter().rposition(|&b| b != b'\r' && b != b'\n').map_or(0, |i| i + 1);
+    let raw = &raw[..end];
+    match std::str::from_utf8(raw) {
+        Ok(line) => (Cow::Borrowed(line), None),
+        Err(e) => (String::from_utf8_lossy(raw), Some(ParseError { kind: ParseErrorKind::InvalidUtf8, offset: e.valid_up_to() })),
+    }
+}
+
+// Parse and handle one raw line from the server, returning the replies to send
+async fn process_line(raw: &[u8], config: &IrcConfig, state: &mut IrcState) -> Vec<String> {
+    let (raw_message, decode_error) = decode_line(raw);
+    let raw_message = raw_message.as_ref();
+    if let Some(e) = decode_error {
+        render!(config, Verbosity::Verbose, "Accepting malformed message ({})", e);
+    }
+    let parsed = match IrcMessage::try_parse(raw_message) {
+        Ok(parsed_message) => Ok(parsed_message),
+        // Out of spec but still readable, so note it and handle the message anyway
+        Err(e) if e.kind != ParseErrorKind::MissingCommand => {
+            render!(config, Verbosity::Verbose, "Accepting malformed message ({})", e);
+            IrcMessage::parse(raw_message).ok_or(e)
+        }
+        Err(e) => Err(e),
+    };
+    match parsed {
+        Ok(parsed_message) => {
+            if parsed_message.stripped_cr {
+                render!(config, Verbosity::Verbose, "Removed embedded CR from message: {}", parsed_message.to_wire(config.unknown_tags));
+            }
+            handle_message(&parsed_message, config, state).await
+        }
+        Err(e) => {
+            render!(config, Verbosity::Normal, "Failed to parse message ({}): {}", e, raw_message);
+            Vec::new()
+        }
+    }
+}
+
+// Run recorded server lines through the same path as a live session, without a network.
+// Returns what we would have sent back and leaves `state` as the session ended, so a captured
+// session can be checked after the fact.
+async fn replay(log: &[u8], config: &IrcConfig, state: &mut IrcState) -> Vec<String> {
+    let mut responses = Vec::new();
+    for line in log.split_inclusive(|&b| b == b'\n') {
+        responses.extend(process_line(line, config, state).await);
+    }
+    responses
+}
+
+async fn send_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &str, terminator: &str) -> Result<(), Box<dyn Error>> {
+    writer.write_all(format!("{}{}", message, terminator).as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn handle_numeric_reply(code: &str, message: &IrcMessage, config: &IrcConfig, state: &mut IrcState) {
+    state.log(SERVER_LOG, &message.to_wire(config.unknown_tags));
+    let params = state.numeric_params(message);
+    match code {
+        "001" => {
+            // The first param is the nick the server actually registered us with
+            if let Some(nick) = message.params.first() {
+                state.info.nick = nick.clone();
+            }
+            state.registered = true;
+            state.info.server_name = message.prefix.clone();
+            // Most servers end the welcome text with our full nick!user@host
+            if let Some((_, user_host)) = message.params.last()
+                .and_then(|welcome| welcome.rsplit(' ').next())
+                .and_then(|mask| mask.split_once('!'))
+            {
+                if let Some((user, host)) = user_host.split_once('@') {
+                    state.info.user = Some(user.to_string());
+                    state.info.host = Some(host.to_string());
+                }
+            }
+            render!(config, Verbosity::Normal, "Connected to server");
+        },
+        "002" => {
+            // Free text, conventionally "Your host is <host>[<addr>/<port>], running version <version>"
+            if let Some(text) = params.last() {
+                let host = text.strip_prefix("Your host is ")
+                    .and_then(|rest| rest.split([',', '[', ' ']).next())
+                    .filter(|host| !host.is_empty());
+                state.info.server_host = host.map(str::to_string);
+                render!(config, Verbosity::Normal, "{}", text);
+            }
+        },
+        "003" => {
+            // Free text, conventionally "This server was created <date>"
+            if let Some(text) = params.last() {
+                let created = text.strip_prefix("This server was created ").unwrap_or(text);
+                state.info.server_created = Some(created.to_string());
+                render!(config, Verbosity::Normal, "{}", text);
+            }
+        },
+        "004" => {
+            // 004 <servername> <version> <user modes> <channel modes> [<channel modes with a parameter>]
+            if params.len() >= 4 {
+                state.info.server_name = Some(params[0].clone());
+                state.info.server_version = Some(params[1].clone());
+                state.info.user_modes = Some(params[2].clone());
+                state.info.channel_modes = Some(params[3].clone());
+                if !state.info.isupport.contains_key("CHANMODES") {
+                    let param_modes = params.get(4).map(String::as_str).unwrap_or("");
+                    state.mode_spec.apply_myinfo(&params[3], param_modes);
+                }
+            }
+        },
+        "005" => {
+            // Drop the trailing "are supported by this server"
+            if params.len() >= 2 {
+                state.apply_isupport(&params[..params.len() - 1]);
+            }
+        },
+        "256" => {
+            // 256 [<server>] :Administrative info, where the server is optional
+            state.admin_info = Some(AdminInfo {
+                server: if params.len() >= 2 { params.first().cloned() } else { None },
+                ..AdminInfo::default()
+            });
+        },
+        "257" | "258" => {
+            if let Some(line) = params.last() {
+                state.admin_info.get_or_insert_with(AdminInfo::default).location.push(line.clone());
+            }
+        },
+        "259" => {
+            let mut info = state.admin_info.take().unwrap_or_default();
+            info.email = params.last().cloned();
+            render!(config, Verbosity::Normal, "Admin info for {}:", info.server.as_deref().unwrap_or("server"));
+            for line in &info.location {
+                render!(config, Verbosity::Normal, "  {}", line);
+            }
+            if let Some(ref email) = info.email {
+                render!(config, Verbosity::Normal, "  Contact: {}", email);
+            }
+        },
+        "332" => {
+            if params.len() >= 2 {
+                let channel = &params[0];
+                let topic = &params[1];
+                if let Some(channel_state) = state.channel_mut(channel) {
+                    channel_state.topic = Some(topic.clone());
+                }
+                render!(config, Verbosity::Normal, "Topic for {}: {}", channel, topic);
+            }
+        },
+        "351" => {
+            if params.len() >= 2 {
+                let version = ServerVersion {
+                    version: params[0].clone(),
+                    server: params[1].clone(),
+                    comments: params.get(2).cloned().unwrap_or_default(),
+                };
+                render!(config, Verbosity::Normal, "{} is running {} {}", version.server, version.version, version.comments);
+                state.server_version = Some(version);
+            }
+        },
+        "353" => {
+            // 353 <symbol> <channel> :<names>
+            if params.len() >= 3 {
+                let channel = &params[1];
+                let users = &params[2];
+                let entries: Vec<_> = users.split_whitespace()
+                    .map(|entry| {
+                        let (nick, status) = state.mode_spec.parse_names_entry(entry);
+                        (state.fold_case(nick), status)
+                    })
+                    .collect();
+                match state.channel_mut(channel) {
+                    Some(channel_state) => {
+                        channel_state.pending_names.get_or_insert_with(HashMap::new).extend(entries);
+                        // The burst right after joining is summarised on 366 rather than shown line by line
+                        if channel_state.names_synced {
+                            render!(config, Verbosity::Normal, "Users in {}: {}", channel, users);
+                        }
+                    }
+                    None => render!(config, Verbosity::Normal, "Users in {}: {}", channel, users),
+                }
+            }
+        },
+        "366" => {
+            if let Some(channel) = params.first() {
+                match state.channel_mut(channel) {
+                    Some(channel_state) if !channel_state.names_synced => {
+                        // Keep anyone who JOINed while the burst was still arriving
+                        channel_state.members.extend(channel_state.pending_names.take().unwrap_or_default());
+                        channel_state.names_synced = true;
+                        render!(config, Verbosity::Normal, "Synced {} users in {}", channel_state.members.len(), channel);
+                    }
+                    Some(channel_state) => {
+                        // A later NAMES is a full snapshot, so it replaces what we had
+                        if let Some(names) = channel_state.pending_names.take() {
+                            channel_state.members = names;
+                        }
+                        render!(config, Verbosity::Normal, "End of names list for {}", channel);
+                    }
+                    None => render!(config, Verbosity::Normal, "End of names list for {}", channel),
+                }
+            }
+        },
+        "372" => {
+            if let Some(msg) = message.params.last() {
+                render!(config, Verbosity::Normal, "{}", msg);
+            }
+        },
+        "375" => render!(config, Verbosity::Normal, "--- Message of the Day ---"),
+        "376" | "422" => {
+            // 422 ERR_NOMOTD ends registration in place of the MOTD on servers that have none
+            if code == "376" {
+                render!(config, Verbosity::Normal, "--- End of MOTD ---");
+            } else {
+                render!(config, Verbosity::Normal, "--- No MOTD ---");
+            }
+            // Registration is done by now, so this is everything we'll learn about the session up front
+            let info = state.connection_info();
+            let mut identity = info.nick.clone();
+            if let (Some(user), Some(host)) = (&info.user, &info.host) {
+                identity = format!("{}!{}@{}", identity, user, host);
+            }
+            render!(config, Verbosity::Normal, "Session: {} on {} running {} ({} ISUPPORT tokens)",
+                identity,
+                info.server_name.as_deref().unwrap_or("unknown server"),
+                info.server_version.as_deref().unwrap_or("unknown version"),
+                info.isupport.len());
+            // Not every server sends 002 and 003
+            if let Some(ref host) = info.server_host {
+                render!(config, Verbosity::Normal, "Server host: {}", host);
+            }
+            if let Some(ref created) = info.server_created {
+                render!(config, Verbosity::Normal, "Server created: {}", created);
+            }
+            if let (Some(user_modes), Some(channel_modes)) = (&info.user_modes, &info.channel_modes) {
+                render!(config, Verbosity::Normal, "Available modes: user {} channel {}", user_modes, channel_modes);
+            }
+            render!(config, Verbosity::Verbose, "{}", state.features(config));
+        },
+        "421" => {
+            // Name the command the server rejected rather than printing its generic text
+            if let Some(command) = params.first() {
+                render!(config, Verbosity::Normal, "Server does not support {}", command);
+            }
+        },
+        "710" => {
+            // 710 <channel> <nick!user@host> :has asked for an invite
+            if params.len() >= 2 {
+                let nick = params[1].split('!').next().unwrap_or(&params[1]);
+                render!(config, Verbosity::Normal, "[{}] * {} is knocking, asking for an invite", params[0], nick);
+            }
+        },
+        "711" | "712" | "713" | "714" => {
+            // Replies to our own KNOCK: delivered, too many knocks, channel is open, already on channel
+            if params.len() >= 2 {
+                render!(config, Verbosity::Normal, "Knock on {}: {}", params[0], params[1]);
+            }
+        },
+        "761" => {
+            // 761 <target> <key> <visibility> [:<value>]
+            if params.len() >= 3 {
+                let value = params.get(3).map(String::as_str);
+                state.set_metadata(&params[0], &params[1], value);
+                render!(config, Verbosity::Normal, "Metadata {} for {}: {}", params[1], params[0], value.unwrap_or("(unset)"));
+            }
+        },
+        "766" => {
+            // 766 <target> <key> :key not set
+            if params.len() >= 2 {
+                state.set_metadata(&params[0], &params[1], None);
+                render!(config, Verbosity::Normal, "Metadata {} for {} is not set", params[1], params[0]);
+            }
+        },
+        _ => {
+            // The above have important information. For the rest, a minimal display suffices
+            if !message.params.is_empty() {
+                if let Some(msg) = message.params.last() {
+                    if msg.len() > 1 {
+                        render!(config, Verbosity::Normal, "{}", msg);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Records a send in `sent` and returns true, unless `limit` sends already happened in the last minute
+fn allow_within_minute(sent: &mut VecDeque<Instant>, limit: usize) -> bool {
+    let now = Instant::now();
+    while sent.front().is_some_and(|&t| now.duration_since(t) >= Duration::from_secs(60)) {
+        sent.pop_front();
+    }
+    if sent.len() >= limit {
+        return false;
+    }
+    sent.push_back(now);
+    true
+}
+
+// Servers quit users lost in a netsplit with the two split servers as the reason, e.g. "hub.example.net leaf.example.net"
+fn is_netsplit_reason(reason: &str) -> bool {
+    let servers: Vec<&str> = reason.split(' ').collect();
+    servers.len() == 2 && servers.iter().all(|server| {
+        server.contains('.') && !server.starts_with('.') && !server.ends_with('.')
+            && server.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '*')
+    })
+}
+
+fn handle_ctcp(target: &str, nick: &str, ctcp: &str, config: &IrcConfig, state: &mut IrcState) -> Option<String> {
+    let (command, args) = ctcp.split_once(' ').unwrap_or((ctcp, ""));
+    let reply = match command {
+        "ACTION" => {
+            render!(config, Verbosity::Quiet, "[{}] * {} {}", target, nick, args);
+            let log_target = if state.is_channel(target) { target } else { nick };
+            state.log(log_target, &format!("* {} {}", nick, args));
+            return None;
+        }
+        "VERSION" => format!("VERSION irc-rs {}", env!("CARGO_PKG_VERSION")),
+        "PING" => format!("PING {}", args),
+        _ => {
+            render!(config, Verbosity::Verbose, "< CTCP {} from {}", command, nick);
+            return None;
+        }
+    };
+
+    if !state.allow_ctcp_reply(nick, config.ctcp_replies_per_minute) {
+        render!(config, Verbosity::Verbose, "Not replying to CTCP {} from {}, too many requests", command, nick);
+        return None;
+    }
+    render!(config, Verbosity::Verbose, "> CTCP {} reply to {}", command, nick);
+    Some(format!("NOTICE {} :\x01{}\x01", nick, reply))
+}
+
+async fn handle_message(message: &IrcMessage, config: &IrcConfig, state: &mut IrcState) -> Vec<String> {
+    match message.command.as_str() {
+        "PING" => {
+            if let Some(server) = message.params.first() {
+                render!(config, Verbosity::Verbose, "< PING {}", server);
+                render!(config, Verbosity::Verbose, "> PONG {}", server);
+                vec![format!("PONG {}", server)]
+            } else {
+                Vec::new()
+            }
+        }
+        "PONG" => {
+            // We never send PINGs of our own yet, so any PONG is unsolicited. Note it and move on
+            // rather than attributing it to anything
+            let token = message.params.last().map(String::as_str).unwrap_or("");
+            render!(config, Verbosity::Verbose, "< PONG {} (not ours, ignored)", token);
+            Vec::new()
+        }
+        "001" => {
+            handle_numeric_reply("001", message, config, state);
+            let mut responses = Vec::new();
+            if let (Ghost::Pending, Some(password)) = (state.ghost, &config.nickserv_password) {
+                responses.push(format!("PRIVMSG NickServ :GHOST {} {}", config.nick, password));
+                state.ghost = Ghost::Sent;
+            }
+            responses.extend(config.channels.first().map(|channel| format!("JOIN {}", channel)));
+            responses
+        }
+        "433" | "437" => {
+            // 433 <attempted nick> :Nickname is already in use
+            // 437 <nick or channel> :Nick/channel is temporarily unavailable
+            let Some(target) = state.numeric_params(message).first() else {
+                return Vec::new();
+            };
+            if state.is_channel(target) {
+                // Juped or still recovering from a split; there's no auto-rejoin yet, so leave retrying to the user
+                render!(config, Verbosity::Normal, "Cannot join {}: channel is temporarily unavailable", target);
+                return Vec::new();
+            }
+            if state.registered {
+                render!(config, Verbosity::Normal, "Nick {} is unavailable", target);
+                if state.ghost == Ghost::Reclaiming {
+                    state.ghost = Ghost::Idle;
+                }
+                return Vec::new();
+            }
+            let collides_with_self = state.fold_case(target) == state.fold_case(&config.nick);
+            if message.command == "433" && collides_with_self && config.ghost_on_collision && config.nickserv_password.is_some() {
+                render!(config, Verbosity::Normal, "Nick {} is in use, will ghost it once registered", target);
+                state.ghost = Ghost::Pending;
+            }
+            let fallback = state.fallback_nick(target);
+            render!(config, Verbosity::Normal, "Nick {} is unavailable, trying {}", target, fallback);
+            state.info.nick = fallback.clone();
+            vec![format!("NICK {}", fallback)]
+        }
+        "432" => {
+            // 432 <nick> :Erroneous nickname
+            let params = state.numeric_params(message);
+            let (Some(target), reason) = (params.first(), params.last()) else {
+                return Vec::new();
+            };
+            if state.registered {
+                render!(config, Verbosity::Normal, "Nick {} was rejected: {}", target, reason.map(String::as_str).unwrap_or(""));
+                if state.ghost == Ghost::Reclaiming {
+                    state.ghost = Ghost::Idle;
+                }
+                return Vec::new();
+            }
+            // Registration can't finish without a nick, so retry with one short enough for any server
+            state.nick_rejected = true;
+            let fallback = state.fallback_nick(target);
+            render!(config, Verbosity::Normal, "Nick {} was rejected, trying {}", target, fallback);
+            state.info.nick = fallback.clone();
+            vec![format!("NICK {}", fallback)]
+        }
+        "PRIVMSG" => {
+            if message.params.len() >= 2 {
+                let channel = &message.params[0];
+                let msg = &message.params[1];
+                if let Some(nick) = message.source_nick() {
+                    state.note_activity(channel, msg);
+                    if let Some(ctcp) = msg.strip_prefix('\x01') {
+                        return handle_ctcp(channel, nick, ctcp.trim_end_matches('\x01'), config, state).into_iter().collect();
+                    }
+                    // Web clients can mark a private message as sent from within a channel
+                    match message.tags.get("+draft/channel-context") {
+                        Some(context) if !state.is_channel(channel) => render!(config, Verbosity::Quiet, "[{} via {}] <{}> {}", channel, printable_tag_value(context), nick, msg),
+                        _ => {
+                            let symbol = state.member_status(channel, nick).and_then(|status| state.mode_spec().highest_symbol(status));
+                            let symbol = symbol.map(String::from).unwrap_or_default();
+                            render!(config, Verbosity::Quiet, "[{}] <{}{}> {}", channel, symbol, nick, msg)
+                        }
+                    }
+                    let log_target = if state.is_channel(channel) { channel } else { nick };
+                    state.log(log_target, &format!("<{}> {}", nick, msg));
+                }
+            }
+            Vec::new()
+        }
+        "NOTICE" => {
+            if message.params.len() >= 2 {
+                let target = &message.params[0];
+                let msg = &message.params[1];
+                let nick = message.source_nick().unwrap_or("server");
+                // CTCP replies come back as NOTICEs
+                if let Some(version) = msg.strip_prefix("\x01VERSION ") {
+                    let version = version.trim_end_matches('\x01');
+                    render!(config, Verbosity::Normal, "* {} is using {}", nick, version);
+                    let key = state.fold_case(nick);
+                    state.client_versions.insert(key, version.to_string());
+                    return Vec::new();
+                }
+                if state.is_channel(target) {
+                    render!(config, Verbosity::Normal, "[{}] -{}- {}", target, nick, msg);
+                } else {
+                    render!(config, Verbosity::Normal, "-{}- {}", nick, msg);
+                }
+                // Notices from users go with their query; ones from the server (no nick!user@host) go to the server log
+                let from_user = message.prefix.as_deref().is_some_and(|p| p.contains('!'));
+                let log_target = if state.is_channel(target) { target } else if from_user { nick } else { SERVER_LOG };
+                state.log(log_target, &format!("-{}- {}", nick, msg));
+                // NickServ answers our GHOST either way; if it worked the nick is free now, and if not we just get a 433
+                if state.ghost == Ghost::Sent && nick.eq_ignore_ascii_case("NickServ") {
+                    if let Err(e) = state.validate_nick(&config.nick) {
+                        render!(config, Verbosity::Normal, "Not reclaiming nick: {}", e);
+                        state.ghost = Ghost::Idle;
+                        return Vec::new();
+                    }
+                    state.ghost = Ghost::Reclaiming;
+                    return vec![format!("NICK {}", config.nick)];
+                }
+            }
+            Vec::new()
+        }
+        "JOIN" => {
+            if let Some(channel) = message.params.first() {
+                if let Some(nick) = message.source_nick() {
+                    if state.is_self(nick) {
+                        // Start from a clean slate; NAMES will fill the member list back in
+                        let key = state.fold_case(channel);
+                        state.channels.insert(key, ChannelState::default());
+                    }
+                    let member = state.fold_case(nick);
+                    if let Some(channel_state) = state.channel_mut(channel) {
+                        channel_state.members.insert(member, StatusFlags::default());
+                    }
+                    render!(config, Verbosity::Normal, "* {} joined {}", nick, channel);
+                    state.log(channel, &format!("* {} joined {}", nick, channel));
+
+                    let queried = config.version_query_channels.iter().any(|c| state.fold_case(c) == state.fold_case(channel));
+                    if queried && !state.is_self(nick) {
+                        // Every joiner is a new nick, so the per-nick limit alone can't stop a burst
+                        if !allow_within_minute(&mut state.version_queries, config.version_queries_per_minute) {
+                            render!(config, Verbosity::Verbose, "Not sending CTCP VERSION to {}, too many queries this minute", nick);
+                        } else if state.allow_ctcp_reply(nick, config.ctcp_replies_per_minute) {
+                            return vec![format!("PRIVMSG {} :\x01VERSION\x01", nick)];
+                        } else {
+                            render!(config, Verbosity::Verbose, "Not sending CTCP VERSION to {}, too many requests", nick);
+                        }
+                    }
+                }
+            }
+            Vec::new()
+        }
+        "PART" => {
+            if let Some(channel) = message.params.first() {
+                if let Some(nick) = message.source_nick() {
+                    let member = state.fold_case(nick);
+                    if state.is_self(nick) {
+                        let key = state.fold_case(channel);
+                        state.channels.remove(&key);
+                    } else if let Some(channel_state) = state.channel_mut(channel) {
+                        channel_state.remove_member(&member);
+                    }
+                    render!(config, Verbosity::Normal, "* {} left {}", nick, channel);
+                    state.log(channel, &format!("* {} left {}", nick, channel));
+                }
+            }
+            Vec::new()
+        }
+        "KICK" => {
+            // KICK <channel> <nick> [:<reason>]
+            if message.params.len() >= 2 {
+                let channel = &message.params[0];
+                let victim = &message.params[1];
+                let kicker = message.source_nick().unwrap_or("server");
+                let member = state.fold_case(victim);
+                if state.is_self(victim) {
+                    let key = state.fold_case(channel);
+                    state.channels.remove(&key);
+                } else if let Some(channel_state) = state.channel_mut(channel) {
+                    channel_state.remove_member(&member);
+                }
+                let line = match message.params.get(2) {
+                    Some(reason) => format!("* {} was kicked from {} by {} ({})", victim, channel, kicker, reason),
+                    None => format!("* {} was kicked from {} by {}", victim, channel, kicker),
+                };
+                render!(config, Verbosity::Normal, "{}", line);
+                state.log(channel, &line);
+            }
+            Vec::new()
+        }
+        "QUIT" => {
+            if let Some(nick) = message.source_nick() {
+                // QUIT carries no channel, so drop the user everywhere or they linger as a phantom member
+                let member = state.fold_case(nick);
+                for channel_state in state.channels.values_mut() {
+                    channel_state.remove_member(&member);
+                }
+                match message.params.first() {
+                    Some(reason) if is_netsplit_reason(reason) => render!(config, Verbosity::Normal, "* {} quit (netsplit: {})", nick, reason),
+                    Some(reason) => render!(config, Verbosity::Normal, "* {} quit ({})", nick, reason),
+                    None => render!(config, Verbosity::Normal, "* {} quit", nick),
+                }
+            }
+            Vec::new()
+        }
+        "NICK" => {
+            // Servers send either `NICK new` or `NICK :new`, both of which parse to a single param
+            if let (Some(new_nick), Some(old_nick)) = (message.params.first(), message.source_nick()) {
+                let mut responses = Vec::new();
+                if state.is_self(old_nick) {
+                    state.info.nick = new_nick.clone();
+                    // Networks that enforce nick ownership rename us again unless we identify for it
+                    if let (Ghost::Reclaiming, Some(password)) = (state.ghost, &config.nickserv_password) {
+                        if state.is_self(&config.nick) {
+                            render!(config, Verbosity::Verbose, "> PRIVMSG NickServ :IDENTIFY ****");
+                            responses.push(format!("PRIVMSG NickServ :IDENTIFY {}", password));
+                            state.ghost = Ghost::Idle;
+                        }
+                    }
+                }
+                let (old_member, new_member) = (state.fold_case(old_nick), state.fold_case(new_nick));
+                for channel_state in state.channels.values_mut() {
+                    channel_state.rename_member(&old_member, &new_member);
+                }
+                render!(config, Verbosity::Normal, "* {} is now known as {}", old_nick, new_nick);
+                return responses;
+            }
+            Vec::new()
+        }
+        "MODE" => {
+            if message.params.len() >= 2 {
+                let target = &message.params[0];
+                let setter = message.source_nick().unwrap_or(target);
+                if state.is_channel(target) {
+                    let changes = state.mode_spec().parse_changes(&message.params[1], &message.params[2..]);
+                    for (adding, mode, arg) in changes {
+                        if let (true, Some(nick)) = (state.mode_spec().takes_nick(mode), &arg) {
+                            let member = state.fold_case(nick);
+                            if let Some(status) = state.channel_mut(target).and_then(|c| c.members.get_mut(&member)) {
+                                status.set(mode, adding);
+                            }
+                        }
+                        let spec = state.mode_spec();
+                        let sign = if adding { '+' } else { '-' };
+                        match arg {
+                            Some(arg) if spec.takes_nick(mode) => render!(config, Verbosity::Normal, "* {} sets {}{} on {} for {}", setter, sign, mode, target, arg),
+                            Some(arg) if spec.takes_mask(mode) => render!(config, Verbosity::Normal, "* {} sets {}{} on {} matching {}", setter, sign, mode, target, arg),
+                            Some(arg) => render!(config, Verbosity::Normal, "* {} sets {}{} {} on {}", setter, sign, mode, arg, target),
+                            None => render!(config, Verbosity::Normal, "* {} sets {}{} on {}", setter, sign, mode, target),
+                        }
+                    }
+                } else {
+                    render!(config, Verbosity::Normal, "* {} sets mode {}", target, message.params[1..].join(" "));
+                }
+            }
+            Vec::new()
+        }
+        "RENAME" => {
+            // RENAME <old> <new> :<reason>
+            if message.params.len() >= 2 {
+                let old = &message.params[0];
+                let new = &message.params[1];
+                // We may see a RENAME for a channel we aren't in; there's just nothing to migrate then
+                let (old_key, new_key) = (state.fold_case(old), state.fold_case(new));
+                if let Some(channel_state) = state.channels.remove(&old_key) {
+                    state.channels.insert(new_key, channel_state);
+                }
+                match message.params.get(2) {
+                    Some(reason) => render!(config, Verbosity::Normal, "* {} is now known as {} ({})", old, new, reason),
+                    None => render!(config, Verbosity::Normal, "* {} is now known as {}", old, new),
+                }
+            }
+            Vec::new()
+        }
+        "FAIL" | "WARN" | "NOTE" => {
+            match StandardReply::from_message(message) {
+                Some(reply) => {
+                    let context = if reply.context.is_empty() { String::new() } else { format!(" [{}]", reply.context.join(" ")) };
+                    render!(config, Verbosity::Normal, "{} {} {}{}: {}", reply.kind, reply.command, reply.code, context, reply.description);
+                }
+                None => render!(config, Verbosity::Normal, "< {}", message.to_wire(config.unknown_tags)),
+            }
+            Vec::new()
+        }
+        "METADATA" => {
+            // METADATA <target> <key> <visibility> [:<value>], sent when a subscribed key changes
+            if message.params.len() >= 3 {
+                let target = &message.params[0];
+                let key = &message.params[1];
+                let value = message.params.get(3).map(String::as_str);
+                match state.set_metadata(target, key, value) {
+                    Some(previous) => render!(config, Verbosity::Normal, "* {} changed {} from {} to {}", target, key, previous, value.unwrap_or("(unset)")),
+                    None => render!(config, Verbosity::Normal, "* {} set {} to {}", target, key, value.unwrap_or("(unset)")),
+                }
+            }
+            Vec::new()
+        }
+        "TAGMSG" => {
+            // Tag-only messages carry client metadata; the only one worth showing is an author deleting their message
+            if let (Some(target), Some(msgid)) = (message.params.first(), message.tags.get("+draft/delete")) {
+                let nick = message.source_nick().unwrap_or("server");
+                render!(config, Verbosity::Normal, "[{}] * {} deleted message {}", target, nick, printable_tag_value(msgid));
+            }
+            Vec::new()
+        }
+        "REDACT" => {
+            // REDACT <target> <msgid> [:<reason>]
+            if message.params.len() >= 2 {
+                let target = &message.params[0];
+                let msgid = &message.params[1];
+                let nick = message.source_nick().unwrap_or("server");
+                match message.params.get(2) {
+                    Some(reason) => render!(config, Verbosity::Normal, "[{}] * {} redacted message {} ({})", target, nick, msgid, reason),
+                    None => render!(config, Verbosity::Normal, "[{}] * {} redacted message {}", target, nick, msgid),
+                }
+            }
+            Vec::new()
+        }
+        _ => {
+            if message.command.chars().all(|c| c.is_ascii_digit()) {
+                handle_numeric_reply(&message.command, message, config, state);
+            } else {
+                render!(config, Verbosity::Normal, "< {}", message.to_wire(config.unknown_tags));
+            }
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        // What emit() showed on this test's thread
+        pub(super) static RENDERED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn take_rendered() -> Vec<String> {
+        RENDERED.with(|rendered| rendered.take())
+    }
+
+    #[test]
+    fn chanmodes_groups_classify_modes() {
+        let mut spec = ModeSpec::default();
+        spec.set_chanmodes("eIbq,k,flj,CFLNPQcgimnprstz");
+        for mode in ['e', 'I', 'b', 'q'] {
+            assert_eq!(spec.kind(mode), Some(ModeKind::List));
+        }
+        assert_eq!(spec.kind('k'), Some(ModeKind::Always));
+        for mode in ['f', 'l', 'j'] {
+            assert_eq!(spec.kind(mode), Some(ModeKind::OnSet));
+        }
+        for mode in "CFLNPQcgimnprstz".chars() {
+            assert_eq!(spec.kind(mode), Some(ModeKind::NoParam));
+        }
+        // PREFIX modes survive a new CHANMODES
+        assert_eq!(spec.kind('o'), Some(ModeKind::Prefix));
+        assert_eq!(spec.kind('X'), None);
+
+        assert!(spec.takes_param('k', false));
+        assert!(spec.takes_param('l', true));
+        assert!(!spec.takes_param('l', false));
+        assert!(!spec.takes_param('m', true));
+        assert!(spec.takes_mask('q'));
+        assert!(spec.takes_nick('o'));
+    }
+
+    #[test]
+    fn ctcp_limit_is_shared_by_equivalent_nicks() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        // "x[" and "x{" are the same nick under rfc1459
+        let allowed = ["x[", "x{", "x[", "x{"].iter().filter(|nick| state.allow_ctcp_reply(nick, 3)).count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[tokio::test]
+    async fn connection_info_follows_the_registration_burst() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        for line in [
+            ":irc.example.net 001 user_ :Welcome to the Example IRC Network user_!ident@203.0.113.7",
+            ":irc.example.net 002 user_ :Your host is irc.example.net[203.0.113.1/6667], running version exampled-2.0",
+            ":irc.example.net 003 user_ :This server was created Mon Jan 1 2024",
+            ":irc.example.net 004 user_ irc.example.net exampled-2.0 iowx bklmnopstv bklov",
+            ":irc.example.net 005 user_ CASEMAPPING=ascii NICKLEN=16 :are supported by this server",
+            ":irc.example.net 422 user_ :MOTD File is missing",
+        ] {
+            process_line(line.as_bytes(), &config, &mut state).await;
+        }
+        let info = state.connection_info();
+        assert_eq!(info.nick, "user_");
+        assert_eq!(info.user.as_deref(), Some("ident"));
+        assert_eq!(info.host.as_deref(), Some("203.0.113.7"));
+        assert_eq!(info.server_name.as_deref(), Some("irc.example.net"));
+        assert_eq!(info.server_host.as_deref(), Some("irc.example.net"));
+        assert_eq!(info.server_created.as_deref(), Some("Mon Jan 1 2024"));
+        assert_eq!(info.server_version.as_deref(), Some("exampled-2.0"));
+        assert_eq!(info.user_modes.as_deref(), Some("iowx"));
+        assert_eq!(info.channel_modes.as_deref(), Some("bklmnopstv"));
+        assert_eq!(info.isupport.get("NICKLEN").map(String::as_str), Some("16"));
+        assert_eq!(info.isupport.get("CASEMAPPING").map(String::as_str), Some("ascii"));
+    }
+
+    #[test]
+    fn tag_values_lose_control_characters_for_display() {
+        let message = IrcMessage::try_parse("@+draft/channel-context=#chan\\r\\n\x1b[2J :bob!b@h PRIVMSG user :hi").unwrap();
+        let context = &message.tags["+draft/channel-context"];
+        assert_eq!(context, "#chan\r\n\x1b[2J");
+        assert_eq!(printable_tag_value(context), "#chan[2J");
+    }
+
+    #[test]
+    fn tag_only_line_is_missing_its_command() {
+        for line in ["@time=2024-01-01T00:00:00Z", "@time=2024-01-01T00:00:00Z ", "@a=b;c :irc.example.net"] {
+            let error = IrcMessage::try_parse(line).err().unwrap();
+            assert_eq!(error.kind, ParseErrorKind::MissingCommand);
+            assert_eq!(error.offset, line.len());
+            assert_eq!(error.to_string(), format!("missing command at byte {}", line.len()));
+            assert!(IrcMessage::parse(line).is_none());
+        }
+    }
+
+    #[test]
+    fn lenient_parse_keeps_over_long_lines() {
+        let line = format!(":bob!b@h PRIVMSG #chan :{}", "x".repeat(600));
+        let error = IrcMessage::try_parse(&line).err().unwrap();
+        assert_eq!(error.kind, ParseErrorKind::TooLong);
+        assert_eq!(error.offset, MAX_LINE_LEN);
+        let message = IrcMessage::parse(&line).unwrap();
+        assert_eq!(message.params, vec!["#chan".to_string(), "x".repeat(600)]);
+
+        assert_eq!(IrcMessage::try_parse("FOO_BAR x").err().unwrap().kind, ParseErrorKind::InvalidCommand);
+        assert_eq!(IrcMessage::parse("FOO_BAR x").unwrap().command, "FOO_BAR");
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_decoded_lossily() {
+        let (line, error) = decode_line(b":bob!b@h PRIVMSG #chan :caf\xe9\r\n");
+        assert_eq!(line, ":bob!b@h PRIVMSG #chan :caf\u{FFFD}");
+        let error = error.unwrap();
+        assert_eq!(error.kind, ParseErrorKind::InvalidUtf8);
+        assert_eq!(error.offset, 27);
+
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        assert_eq!(process_line(b"PING :caf\xe9\r\n", &config, &mut state).await, vec!["PONG caf\u{FFFD}".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn overlong_nick_is_not_reclaimed() {
+        let long_nick = "d".repeat(40);
+        let config = IrcConfig {
+            nick: long_nick.clone(),
+            nickserv_password: Some("hunter2".to_string()),
+            ..IrcConfig::default()
+        };
+        let mut state = IrcState::new(&config);
+        let fallback = format!("{}_", long_nick);
+        for (line, expected) in [
+            (format!(":irc.example.net 433 * {} :Nickname is already in use", long_nick), vec![format!("NICK {}", fallback)]),
+            (format!(":irc.example.net 001 {} :Welcome", fallback), vec![format!("PRIVMSG NickServ :GHOST {} hunter2", long_nick), "JOIN #general".to_string()]),
+            (format!(":irc.example.net 005 {} NICKLEN=16 :are supported by this server", fallback), vec![]),
+            (format!(":NickServ!services@services.example.net NOTICE {} :{} has been ghosted", fallback, long_nick), vec![]),
+        ] {
+            assert_eq!(process_line(line.as_bytes(), &config, &mut state).await, expected, "{}", line);
+        }
+        assert_eq!(state.ghost, Ghost::Idle);
+        assert!(state.validate_nick(&"d".repeat(16)).is_ok());
+
+        // After a 432 with no NICKLEN known, the RFC's 9 is all we can count on
+        let mut state = IrcState::new(&config);
+        assert!(state.validate_nick(&long_nick).is_ok());
+        state.nick_rejected = true;
+        assert!(state.validate_nick(&long_nick).is_err());
+        assert!(state.validate_nick("ninechars").is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejected_nicks_fall_back_within_the_length_limit() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        for (line, expected) in [
+            (":irc.example.net 433 * verylongnick :Nickname is already in use", "NICK verylongnick_"),
+            (":irc.example.net 432 * verylongnick_ :Erroneous nickname", "NICK verylong0"),
+            (":irc.example.net 433 * verylong0 :Nickname is already in use", "NICK verylong1"),
+            (":irc.example.net 433 * short :Nickname is already in use", "NICK short_"),
+        ] {
+            assert_eq!(process_line(line.as_bytes(), &config, &mut state).await, vec![expected.to_string()]);
+        }
+    }
+
+    #[test]
+    fn casemappings_fold_to_lowercase() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        assert_eq!(state.fold_case("Nick[]\\^"), "nick{}|~");
+        assert_eq!(state.fold_case("nick{}|~"), "nick{}|~");
+        state.apply_isupport(&["CASEMAPPING=strict-rfc1459".to_string()]);
+        assert_eq!(state.fold_case("Nick[]\\^"), "nick{}|^");
+        state.apply_isupport(&["CASEMAPPING=ascii".to_string()]);
+        assert_eq!(state.fold_case("Nick[]\\^"), "nick[]\\^");
+    }
+
+    #[tokio::test]
+    async fn replaying_the_recorded_session() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        let responses = replay(include_bytes!("../fixtures/session.log"), &config, &mut state).await;
+        assert_eq!(responses, vec![
+            "NICK user_".to_string(),
+            "JOIN #general".to_string(),
+            "PONG irc.example.net".to_string(),
+            format!("NOTICE bob :\x01VERSION irc-rs {}\x01", env!("CARGO_PKG_VERSION")),
+        ]);
+
+        assert_eq!(state.info.nick, "user_");
+        let general = &state.channels["#general"];
+        assert_eq!(general.topic.as_deref(), Some("Welcome to #general"));
+        let mut members: Vec<_> = general.members.keys().map(String::as_str).collect();
+        members.sort();
+        assert_eq!(members, vec!["alice", "bobby", "user_"]);
+        assert!(state.is_op("#general", "alice"));
+        // +o-v bob bob, before the rename
+        assert!(state.is_op("#general", "bobby"));
+        assert!(!state.is_voiced("#general", "bobby"));
+        assert!(state.member_status("#general", "bob").is_none());
+        assert!(state.member_status("#general", "carol").is_none());
+    }
+
+    #[tokio::test]
+    async fn self_collision_ghosts_then_identifies() {
+        let config = IrcConfig {
+            nick: "me".to_string(),
+            nickserv_password: Some("hunter2".to_string()),
+            ..IrcConfig::default()
+        };
+        let mut state = IrcState::new(&config);
+        for (line, expected) in [
+            (":irc.example.net 433 * me :Nickname is already in use", vec!["NICK me_"]),
+            // Unrelated NickServ chatter before the GHOST goes out mustn't trigger the reclaim
+            (":NickServ!services@services.example.net NOTICE me_ :This nickname is registered", vec![]),
+            (":irc.example.net 001 me_ :Welcome me_!me@203.0.113.7", vec!["PRIVMSG NickServ :GHOST me hunter2", "JOIN #general"]),
+            (":NickServ!services@services.example.net NOTICE me_ :me has been ghosted", vec!["NICK me"]),
+            (":me_!me@203.0.113.7 NICK :me", vec!["PRIVMSG NickServ :IDENTIFY hunter2"]),
+            (":NickServ!services@services.example.net NOTICE me :You are now identified", vec![]),
+        ] {
+            assert_eq!(process_line(line.as_bytes(), &config, &mut state).await, expected, "{}", line);
+        }
+        assert_eq!(state.info.nick, "me");
+
+        // A reclaim the server refuses is abandoned, so a later NICK to our nick doesn't IDENTIFY
+        let mut state = IrcState::new(&config);
+        for (line, expected) in [
+            (":irc.example.net 433 * me :Nickname is already in use", vec!["NICK me_"]),
+            (":irc.example.net 001 me_ :Welcome me_!me@203.0.113.7", vec!["PRIVMSG NickServ :GHOST me hunter2", "JOIN #general"]),
+            (":NickServ!services@services.example.net NOTICE me_ :me has been ghosted", vec!["NICK me"]),
+            (":irc.example.net 432 me_ me :Erroneous nickname", vec![]),
+        ] {
+            assert_eq!(process_line(line.as_bytes(), &config, &mut state).await, expected, "{}", line);
+        }
+        assert_eq!(state.ghost, Ghost::Idle);
+        assert_eq!(process_line(b":me_!me@203.0.113.7 NICK :me", &config, &mut state).await, Vec::<String>::new());
+    }
+
+    #[test]
+    fn chat_logs_go_to_separate_sanitized_files() {
+        let dir = std::env::temp_dir().join(format!("irc-rs-logs-{}", std::process::id()));
+        let template = format!("{}/{{network}}/{{channel}}.log", dir.display());
+        let mut chat_log = ChatLog::new(&template, "irc.example.net");
+        chat_log.write("#a", "<alice> one");
+        chat_log.write("#b/../c", "<bob> two");
+        chat_log.write("server", "<server> a query from a user called server");
+        chat_log.write(SERVER_LOG, "001 welcome");
+
+        let network = dir.join("irc.example.net");
+        let read = |name: &str| std::fs::read_to_string(network.join(name)).unwrap();
+        assert!(read("#a.log").ends_with(" <alice> one\n"));
+        assert!(read("#b_.._c.log").ends_with(" <bob> two\n"));
+        assert!(read("server.log").contains("a query"));
+        assert!(!read("server!.log").contains("a query"));
+
+        for i in 0..MAX_OPEN_LOGS + 8 {
+            chat_log.write(&format!("nick{}", i), "hi");
+        }
+        assert_eq!(chat_log.files.len(), MAX_OPEN_LOGS);
+        // An evicted file is reopened and appended to
+        chat_log.write("#a", "<alice> three");
+        assert_eq!(read("#a.log").lines().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trailing_param_keeps_its_spaces() {
+        let message = IrcMessage::try_parse("PRIVMSG   #chan   :hi  ").unwrap();
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, vec!["#chan".to_string(), "hi  ".to_string()]);
+
+        let message = IrcMessage::try_parse(":bob!b@h PRIVMSG #chan :  two  spaces ").unwrap();
+        assert_eq!(message.params, vec!["#chan".to_string(), "  two  spaces ".to_string()]);
+        let message = IrcMessage::try_parse(":bob!b@h PRIVMSG #chan :").unwrap();
+        assert_eq!(message.params, vec!["#chan".to_string(), String::new()]);
+    }
+
+    #[tokio::test]
+    async fn kick_removes_the_member() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        for line in [
+            ":user!user@h JOIN #chan",
+            ":irc.example.net 353 user = #chan :user @alice @bob",
+            ":irc.example.net 366 user #chan :End of /NAMES list.",
+            ":alice!a@h KICK #chan bob :bye",
+        ] {
+            process_line(line.as_bytes(), &config, &mut state).await;
+        }
+        assert!(state.is_op("#chan", "alice"));
+        assert!(!state.is_op("#chan", "bob"));
+        assert!(state.member_status("#chan", "bob").is_none());
+
+        process_line(b":alice!a@h KICK #chan user", &config, &mut state).await;
+        assert!(!state.channels.contains_key("#chan"));
+    }
+
+    #[tokio::test]
+    async fn version_queries_on_join_are_rate_limited_overall() {
+        let config = IrcConfig {
+            version_query_channels: vec!["#census".to_string()],
+            ..IrcConfig::default()
+        };
+        let mut state = IrcState::new(&config);
+        process_line(b":user!user@h JOIN #census", &config, &mut state).await;
+        let mut queries = Vec::new();
+        for i in 0..50 {
+            let line = format!(":nick{}!n@h JOIN #census", i);
+            queries.extend(process_line(line.as_bytes(), &config, &mut state).await);
+        }
+        assert_eq!(queries.len(), config.version_queries_per_minute);
+        assert_eq!(queries[0], "PRIVMSG nick0 :\x01VERSION\x01");
+        // Joins elsewhere aren't queried at all
+        assert!(process_line(b":other!o@h JOIN #general", &config, &mut state).await.is_empty());
+    }
+
+    #[test]
+    fn unknown_tags_follow_the_policy_on_the_way_out() {
+        let line = "@+example.com/vendor=a\\sb\\:c;msgid=abc;time=2024-01-01T00:00:00.000Z :bob!b@h PRIVMSG #chan :hello there";
+        let message = IrcMessage::try_parse(line).unwrap();
+        assert_eq!(message.tags["+example.com/vendor"], "a b;c");
+
+        let passthrough = message.to_wire(TagPolicy::Passthrough);
+        assert_eq!(passthrough, line);
+        let reparsed = IrcMessage::try_parse(&passthrough).unwrap();
+        assert_eq!(reparsed.tags, message.tags);
+        assert_eq!(reparsed.params, message.params);
+
+        assert_eq!(message.to_wire(TagPolicy::Strip),
+            "@msgid=abc;time=2024-01-01T00:00:00.000Z :bob!b@h PRIVMSG #chan :hello there");
+        let untagged = IrcMessage::try_parse("@+vendor/only :bob!b@h PRIVMSG #chan hi").unwrap();
+        assert_eq!(untagged.to_wire(TagPolicy::Strip), ":bob!b@h PRIVMSG #chan hi");
+        assert_eq!(untagged.to_wire(TagPolicy::Passthrough), "@+vendor/only :bob!b@h PRIVMSG #chan hi");
+    }
+
+    #[tokio::test]
+    async fn admin_reply_server_is_optional() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        process_line(b":irc.example.net 256 user :Administrative info", &config, &mut state).await;
+        assert_eq!(state.admin_info.as_ref().unwrap().server, None);
+        process_line(b":irc.example.net 256 user irc.example.net :Administrative info", &config, &mut state).await;
+        assert_eq!(state.admin_info.as_ref().unwrap().server.as_deref(), Some("irc.example.net"));
+    }
+
+    #[tokio::test]
+    async fn version_reply_is_kept() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        process_line(b":irc.example.net 351 user ircd-2.11.2. irc.example.net :TS6ow", &config, &mut state).await;
+        let version = state.server_version.as_ref().unwrap();
+        assert_eq!((version.version.as_str(), version.server.as_str(), version.comments.as_str()), ("ircd-2.11.2.", "irc.example.net", "TS6ow"));
+        process_line(b":irc.example.net 351 user ircd-2.11.3. irc.example.net", &config, &mut state).await;
+        let version = state.server_version.as_ref().unwrap();
+        assert_eq!((version.version.as_str(), version.comments.as_str()), ("ircd-2.11.3.", ""));
+    }
+
+    #[tokio::test]
+    async fn connect_falls_over_to_the_next_server() {
+        // Bind and drop a listener to get a port that refuses connections
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let mut config = IrcConfig {
+            servers: vec![("127.0.0.1".to_string(), refused), ("127.0.0.1".to_string(), open)],
+            connect_timeout: Duration::from_secs(5),
+            ..IrcConfig::default()
+        };
+        let (_stream, active) = connect_any(&config).await.unwrap();
+        assert_eq!(active, ("127.0.0.1".to_string(), open));
+
+        config.servers.truncate(1);
+        assert!(connect_any(&config).await.is_err());
+        config.servers.clear();
+        assert!(connect_any(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rename_moves_channel_state_to_the_new_key() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        for line in [
+            ":user!user@h JOIN #Old",
+            ":irc.example.net 332 user #Old :the topic",
+            ":irc.example.net 353 user = #Old :user @alice",
+            ":irc.example.net 366 user #Old :End of /NAMES list.",
+            ":irc.example.net RENAME #Old #New[1] :moving",
+            // Not a channel we're in, so there's nothing to move
+            ":irc.example.net RENAME #elsewhere #other :moving",
+        ] {
+            assert!(process_line(line.as_bytes(), &config, &mut state).await.is_empty());
+        }
+        assert!(!state.channels.contains_key("#old"));
+        let renamed = &state.channels["#new{1}"];
+        assert_eq!(renamed.topic.as_deref(), Some("the topic"));
+        assert!(state.is_op("#NEW[1]", "alice"));
+        assert!(state.member_status("#new{1}", "user").is_some());
+        assert_eq!(state.channels.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn nick_changes_in_either_form() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        process_line(b":user!u@h NICK newnick", &config, &mut state).await;
+        assert_eq!(state.info.nick, "newnick");
+        process_line(b":newnick!u@h NICK :other", &config, &mut state).await;
+        assert_eq!(state.info.nick, "other");
+        // Someone else's change leaves ours alone
+        process_line(b":bob!b@h NICK mine", &config, &mut state).await;
+        assert_eq!(state.info.nick, "other");
+    }
+
+    #[test]
+    fn source_nick_comes_from_the_prefix() {
+        assert_eq!(IrcMessage::try_parse(":bob!b@h PRIVMSG #c :hi").unwrap().source_nick(), Some("bob"));
+        assert_eq!(IrcMessage::try_parse(":irc.example.net NOTICE * :hi").unwrap().source_nick(), Some("irc.example.net"));
+        assert_eq!(IrcMessage::try_parse("PING :x").unwrap().source_nick(), None);
+    }
+
+    #[test]
+    fn user_hostname_goes_in_the_user_line() {
+        let config = IrcConfig::default();
+        assert_eq!(registration_lines(&config).unwrap(), vec!["NICK user", "USER user 0 * :user"]);
+
+        let config = IrcConfig {
+            user_hostname: Some("gateway.example.net".to_string()),
+            realname: "Some User".to_string(),
+            ..IrcConfig::default()
+        };
+        assert_eq!(registration_lines(&config).unwrap(), vec!["NICK user", "USER user 0 gateway.example.net :Some User"]);
+
+        for bad in ["two words", ":colon", ""] {
+            let config = IrcConfig {
+                user_hostname: Some(bad.to_string()),
+                ..IrcConfig::default()
+            };
+            assert!(registration_lines(&config).unwrap_err().contains("user_hostname"), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn webirc_and_pass_come_first() {
+        let mut config = IrcConfig {
+            webirc: Some(WebircConfig {
+                password: "gwsecret".to_string(),
+                gateway: "gateway".to_string(),
+                hostname: "client.example.net".to_string(),
+                ip: "::1".to_string(),
+            }),
+            server_password: Some("hunter2".to_string()),
+            ..IrcConfig::default()
+        };
+        let lines = registration_lines(&config).unwrap();
+        assert_eq!(lines, vec![
+            "WEBIRC gwsecret gateway client.example.net 0::1",
+            "PASS hunter2",
+            "NICK user",
+            "USER user 0 * :user",
+        ]);
+        let shown: Vec<String> = lines.iter().map(|line| redacted(line)).collect();
+        assert_eq!(shown[..2], ["WEBIRC **** gateway client.example.net 0::1", "PASS ****"]);
+
+        config.webirc.as_mut().unwrap().ip = "2001:db8::7".to_string();
+        assert_eq!(registration_lines(&config).unwrap()[0], "WEBIRC gwsecret gateway client.example.net 2001:db8::7");
+        config.webirc.as_mut().unwrap().ip = "not an ip".to_string();
+        assert!(registration_lines(&config).unwrap_err().contains("webirc ip"));
+    }
+
+    #[tokio::test]
+    async fn unavailable_resource_depends_on_the_target() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        // A channel-form 437 is only reported; there's no nick to fall back from
+        assert!(process_line(b":irc.example.net 437 * #juped :Nick/channel is temporarily unavailable", &config, &mut state).await.is_empty());
+        assert_eq!(state.info.nick, "user");
+        assert_eq!(
+            process_line(b":irc.example.net 437 * user :Nick/channel is temporarily unavailable", &config, &mut state).await,
+            vec!["NICK user_"]);
+        assert_eq!(state.info.nick, "user_");
+    }
+
+    #[tokio::test]
+    async fn join_after_our_nick_change_is_ours() {
+        let config = IrcConfig::default();
+        let mut state = IrcState::new(&config);
+        process_line(b":user!u@h NICK :newme", &config, &mut state).await;
+        process_line(b":NewMe!u@h JOIN #c", &config, &mut state).await;
+        assert!(state.channels.contains_key("#c"));
+        assert!(state.member_status("#c", "newme").is_some());
+        // Someone else's JOIN to a channel we're not in isn't tracked
+        process_line(b":user!u@h JOIN #d", &config, &mut state).await;
+        assert!(!state.channels.contains_key("#d"));
+    }
+
+    #[test]
+    fn config_reads_from_any_reader() {
+        let text = r##"
+# Comments and blank lines are skipped
+servers = ["irc.example.net:6697", "backup.example.net"]  # fallback uses the default port
+nick = "censusbot"
+channels = ["#a", "#b"]
+verbosity = "quiet"
+unknown_tags = "strip"
+idle_disconnect = 600
+nickserv_password = "p\"w#1"
+ghost_on_collision = false
+version_query_channels = [ "#census", ]
+
+[webirc]
+password = "secret"
+gateway = "web"
+hostname = "client.example.net"
+ip = "203.0.113.9"
+"##;
+        let config = IrcConfig::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(config.servers, vec![("irc.example.net".to_string(), 6697), ("backup.example.net".to_string(), 6667)]);
+        assert_eq!(config.nick, "censusbot");
+        assert_eq!(config.channels, vec!["#a", "#b"]);
+        assert_eq!(config.verbosity, Verbosity::Quiet);
+        assert_eq!(config.unknown_tags, TagPolicy::Strip);
+        assert_eq!(config.idle_disconnect, Some(Duration::from_secs(600)));
+        assert_eq!(config.nickserv_password.as_deref(), Some("p\"w#1"));
+        assert!(!config.ghost_on_collision);
+        assert_eq!(config.version_query_channels, vec!["#census"]);
+        let webirc = config.webirc.unwrap();
+        assert_eq!((webirc.password.as_str(), webirc.ip.as_str()), ("secret", "203.0.113.9"));
+        // Unset keys keep their defaults
+        assert_eq!(config.username, "user");
+
+        for (bad, error) in [
+            ("nick = censusbot", "config line 1: invalid value censusbot"),
+            ("\n\nnick = 5", "config line 3: nick must be a string"),
+            ("colour = \"red\"", "config line 1: unknown key colour"),
+            ("channels = [\"#a\" \"#b\"]", "config line 1: expected ',' or ']' in array"),
+            ("[webirc]\npassword = \"x\"", "config [webirc] needs gateway"),
+        ] {
+            assert_eq!(IrcConfig::from_reader(bad.as_bytes()).err().unwrap().to_string(), error);
+        }
+    }
+
+    #[tokio::test]
+    async fn verbosity_decides_what_is_shown() {
+        let lines = [
+            "PING :irc.example.net",
+            ":alice!a@h JOIN #general",
+            ":irc.example.net 372 user :- Be nice.",
+            ":irc.example.net WALLOPS :something unhandled",
+            ":irc.example.net 710 #general bob!b@h :has asked for an invite",
+            ":alice!a@h PRIVMSG #general :hello",
+        ];
+        let mut shown = Vec::new();
+        for verbosity in [Verbosity::Quiet, Verbosity::Normal, Verbosity::Verbose] {
+            let config = IrcConfig { verbosity, ..IrcConfig::default() };
+            let mut state = IrcState::new(&config);
+            take_rendered();
+            for line in lines {
+                process_line(line.as_bytes(), &config, &mut state).await;
+            }
+            shown.push(take_rendered());
+        }
+        assert_eq!(shown[0], vec!["[#general] <alice> hello"]);
+        assert_eq!(shown[1], vec![
+            "* alice joined #general",
+            "- Be nice.",
+            "< :irc.example.net WALLOPS :something unhandled",
+            "[#general] * bob is knocking, asking for an invite",
+            "[#general] <alice> hello",
+        ]);
+        assert_eq!(shown[2][..2], ["< PING irc.example.net", "> PONG irc.example.net"]);
+        assert_eq!(shown[2][2..], shown[1][..]);
+        assert!(Verbosity::Normal.shows(Verbosity::Quiet) && !Verbosity::Normal.shows(Verbosity::Verbose));
+    }
+
+    #[test]
+    fn numeric_params_drops_only_our_target() {
+        let config = IrcConfig { nick: "Dave^".to_string(), ..IrcConfig::default() };
+        let state = IrcState::new(&config);
+        let params = |line: &str| state.numeric_params(&IrcMessage::parse(line).unwrap()).to_vec();
+
+        assert_eq!(params(":irc.example.net 433 * Dave^ :Nickname is already in use"), ["Dave^", "Nickname is already in use"]);
+        assert_eq!(params(":irc.example.net 332 dave~ #general :Welcome"), ["#general", "Welcome"]);
+        assert_eq!(params(":irc.example.net 710 #general alice!a@h :has asked for an invite"), ["#general", "alice!a@h", "has asked for an invite"]);
+    }
+
+    #[tokio::test]
+    async fn idle_session_quits() {
+        let config = IrcConfig {
+            nick: "dave".to_string(),
+            idle_disconnect: Some(Duration::from_millis(100)),
+            ..IrcConfig::default()
+        };
+        let mut state = IrcState::new(&config);
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(client);
+        // Channel chatter that only contains our nick inside another word isn't addressed to us
+        server.write_all(b":alice!a@h PRIVMSG #general :daves, you there?\r\n").await.unwrap();
+
+        let started = Instant::now();
+        run_session(BufReader::new(reader), writer, &config, &mut state).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let mut sent = String::new();
+        BufReader::new(server).read_line(&mut sent).await.unwrap();
+        assert_eq!(sent, "QUIT :Idle\r\n");
+
+        assert!(mentions("dave: ping", "dave"));
+        assert!(mentions("hey dave", "dave"));
+        assert!(!mentions("daves here", "dave"));
+        assert!(!mentions("dave_ here", "dave"));
+        assert!(!mentions("[dave]", "dave"));
+        assert!(mentions("(dave)", "dave"));
+    }
+
+    // A connection the server has stopped reading from
+    struct BrokenWriter;
+
+    impl AsyncWrite for BrokenWriter {
+        fn poll_write(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>, _: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_send_keeps_reading() {
+        let config = IrcConfig { nick: "dave".to_string(), verbosity: Verbosity::Verbose, ..IrcConfig::default() };
+        let mut state = IrcState::new(&config);
+        let input: &[u8] = b"PING :irc.example.net\r\nPING :irc.example.net\r\n:dave!d@h NICK :renamed\r\n";
+        take_rendered();
+        run_session(input, BrokenWriter, &config, &mut state).await.unwrap();
+        assert_eq!(state.info.nick, "renamed");
+        let rendered = take_rendered();
+        assert_eq!(rendered.iter().filter(|line| line.starts_with("Failed to send")).count(), 1);
+        assert!(rendered.contains(&"Not sending PONG irc.example.net, connection is closing".to_string()));
+        assert_eq!(rendered.last().unwrap(), "Connection closed");
+    }
+
+    #[tokio::test]
+    async fn names_burst_merges_then_later_names_replace() {
+        let config = IrcConfig { nick: "me".to_string(), ..IrcConfig::default() };
+        let mut state = IrcState::new(&config);
+        let members = |state: &IrcState| {
+            let mut nicks: Vec<String> = state.channels["#general"].members.keys().cloned().collect();
+            nicks.sort();
+            nicks
+        };
+        for line in [
+            ":me!m@h JOIN #general",
+            ":irc.example.net 353 me = #general :me @alice bob carol",
+            // Changes while the burst is still arriving apply to names already listed
+            ":eve!e@h JOIN #general",
+            ":bob!b@h PART #general",
+            ":carol!c@h NICK :carla",
+            ":irc.example.net 353 me = #general :dan frank",
+            ":me!m@h KICK #general dan :bye",
+            ":irc.example.net 366 me #general :End of /NAMES list.",
+        ] {
+            process_line(line.as_bytes(), &config, &mut state).await;
+        }
+        assert_eq!(members(&state), ["alice", "carla", "eve", "frank", "me"]);
+
+        for line in [
+            ":frank!f@h QUIT :Client Quit",
+            ":irc.example.net 353 me = #general :me @alice",
+            ":irc.example.net 366 me #general :End of /NAMES list.",
+        ] {
+            process_line(line.as_bytes(), &config, &mut state).await;
+        }
+        assert_eq!(members(&state), ["alice", "me"]);
+    }
+
+    #[tokio::test]
+    async fn standard_replies_are_server_events() {
+        let message = IrcMessage::parse("FAIL JOIN NEED_REGISTRATION #chan :You must register").unwrap();
+        let reply = StandardReply::from_message(&message).unwrap();
+        assert_eq!((reply.kind.as_str(), reply.command.as_str(), reply.code.as_str()), ("FAIL", "JOIN", "NEED_REGISTRATION"));
+        assert_eq!(reply.context, ["#chan"]);
+        assert_eq!(reply.description, "You must register");
+        assert!(StandardReply::from_message(&IrcMessage::parse("FAIL JOIN :no code").unwrap()).is_none());
+
+        for (verbosity, expected) in [
+            (Verbosity::Quiet, vec![]),
+            (Verbosity::Normal, vec!["FAIL JOIN NEED_REGISTRATION [#chan]: You must register".to_string()]),
+        ] {
+            let config = IrcConfig { verbosity, ..IrcConfig::default() };
+            let mut state = IrcState::new(&config);
+            take_rendered();
+            process_line(b"FAIL JOIN NEED_REGISTRATION #chan :You must register", &config, &mut state).await;
+            assert_eq!(take_rendered(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_cache_follows_replies_and_changes() {
+        let config = IrcConfig { nick: "me".to_string(), ..IrcConfig::default() };
+        let mut state = IrcState::new(&config);
+        let key = |target: &str, key: &str| (target.to_string(), key.to_string());
+        process_line(b":irc.example.net 761 me Alice avatar * :https://example.net/a.png", &config, &mut state).await;
+        assert_eq!(state.metadata.get(&key("alice", "avatar")).map(String::as_str), Some("https://example.net/a.png"));
+
+        process_line(b":irc.example.net METADATA alice avatar * :https://example.net/b.png", &config, &mut state).await;
+        assert_eq!(state.metadata.get(&key("alice", "avatar")).map(String::as_str), Some("https://example.net/b.png"));
+        process_line(b":irc.example.net METADATA #general url * :https://example.net", &config, &mut state).await;
+        assert_eq!(state.metadata.len(), 2);
+
+        process_line(b":irc.example.net 766 me ALICE avatar :key not set", &config, &mut state).await;
+        assert!(!state.metadata.contains_key(&key("alice", "avatar")));
+        process_line(b":irc.example.net METADATA #general url *", &config, &mut state).await;
+        assert!(state.metadata.is_empty());
+    }
+
+    #[tokio::test]
+    async fn embedded_cr_is_removed_from_the_body() {
+        let message = IrcMessage::try_parse(":bob!b@h PRIVMSG #chan :fake\rreal line").unwrap();
+        assert!(message.stripped_cr);
+        assert_eq!(message.params, ["#chan", "fakereal line"]);
+        assert!(!IrcMessage::try_parse(":bob!b@h PRIVMSG #chan :plain").unwrap().stripped_cr);
+
+        let config = IrcConfig { verbosity: Verbosity::Verbose, ..IrcConfig::default() };
+        let mut state = IrcState::new(&config);
+        take_rendered();
+        process_line(b":bob!b@h PRIVMSG #chan :fake\rreal line\r\n", &config, &mut state).await;
+        assert_eq!(take_rendered(), [
+            "Removed embedded CR from message: :bob!b@h PRIVMSG #chan :fakereal line",
+            "[#chan] <bob> fakereal line",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn features_reflect_config_and_isupport() {
+        let config = IrcConfig {
+            nick: "me".to_string(),
+            webirc: Some(WebircConfig {
+                password: "secret".to_string(),
+                gateway: "gateway".to_string(),
+                hostname: "client.example.net".to_string(),
+                ip: "198.51.100.7".to_string(),
+            }),
+            nickserv_password: Some("hunter2".to_string()),
+            version_query_channels: vec!["#general".to_string()],
+            ..IrcConfig::default()
+        };
+        let mut state = IrcState::new(&config);
+        state.active_server = Some(("irc.example.net".to_string(), 6667));
+        process_line(b":irc.example.net 005 me NICKLEN=16 CASEMAPPING=ascii EXCEPTS :are supported by this server", &config, &mut state).await;
+        process_line(b":irc.example.net 005 me CHANTYPES=# AWAYLEN=200 :are supported by this server", &config, &mut state).await;
+
+        let features = state.features(&config);
+        assert_eq!(features.server, Some(("irc.example.net".to_string(), 6667)));
+        assert_eq!(features.isupport, ["AWAYLEN=200", "CASEMAPPING=ascii", "CHANTYPES=#", "EXCEPTS", "NICKLEN=16"]);
+        assert_eq!(features.casemapping, "ascii");
+        assert!(features.webirc && features.ghost_on_collision);
+        assert!(!features.chat_logging);
+        assert_eq!(features.idle_disconnect, None);
+        assert_eq!(features.version_query_channels, ["#general"]);
+        assert_eq!((features.ctcp_replies_per_minute, features.version_queries_per_minute), (3, 10));
+
+        let state = IrcState::new(&IrcConfig::default());
+        let features = state.features(&IrcConfig::default());
+        assert!(features.isupport.is_empty());
+        assert_eq!(features.casemapping, "rfc1459");
+        assert!(!features.webirc && !features.ghost_on_collision);
+    }
+
+    #[tokio::test]
+    async fn sessions_keep_separate_state() {
+        let alice_config = IrcConfig { nick: "alice".to_string(), ..IrcConfig::default() };
+        let bob_config = IrcConfig { nick: "bob".to_string(), channels: vec!["#other".to_string()], ..IrcConfig::default() };
+        let mut alice = IrcState::new(&alice_config);
+        let mut bob = IrcState::new(&bob_config);
+
+        process_line(b":irc.example.net 001 alice :Welcome alice!a@198.51.100.1", &alice_config, &mut alice).await;
+        process_line(b":irc.example.net 001 bob :Welcome bob!b@203.0.113.2", &bob_config, &mut bob).await;
+        process_line(b":irc.example.net 005 bob NICKLEN=9 :are supported by this server", &bob_config, &mut bob).await;
+        process_line(b":alice!a@198.51.100.1 JOIN #general", &alice_config, &mut alice).await;
+        process_line(b":bob!b@203.0.113.2 JOIN #other", &bob_config, &mut bob).await;
+        // Each session only treats its own nick as itself
+        process_line(b":alice!a@198.51.100.1 NICK :alicia", &bob_config, &mut bob).await;
+
+        assert_eq!((alice.info.nick.as_str(), bob.info.nick.as_str()), ("alice", "bob"));
+        assert!(alice.registered && bob.registered);
+        assert_eq!(alice.channels.keys().collect::<Vec<_>>(), ["#general"]);
+        assert_eq!(bob.channels.keys().collect::<Vec<_>>(), ["#other"]);
+        assert!(alice.info.isupport.is_empty());
+        assert_eq!(bob.info.isupport.get("NICKLEN").map(String::as_str), Some("9"));
+    }
+
+    #[test]
+    fn mode_changes_pair_with_their_arguments() {
+        let mut spec = ModeSpec::default();
+        spec.set_chanmodes("eIbq,k,flj,CFLNPQcgimnprstz");
+        let args = ["*!*@spam".to_string(), "10".to_string(), "alice".to_string()];
+        assert_eq!(spec.parse_changes("+bml-lo", &args), vec![
+            (true, 'b', Some("*!*@spam".to_string())),
+            (true, 'm', None),
+            (true, 'l', Some("10".to_string())),
+            (false, 'l', None),
+            (false, 'o', Some("alice".to_string())),
+        ]);
+    }
+}